@@ -1,11 +1,17 @@
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
 
 #[derive(Serialize, Deserialize, Hash, Clone, Debug)]
 pub struct UserInfo {
     pub email: String,
     pub is_logged_in: bool,
     pub is_admin: bool,
+    /// May look at admin pages without changing anything
+    pub is_observer: bool,
     pub assigned_number: Option<i32>,
+    /// Groups and roles from the identity provider's configured role claims
+    pub roles: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Hash, Clone)]
@@ -14,4 +20,432 @@ pub struct ServerSentData {
     pub assigned_number: Option<i32>,
     pub done_numbers: Vec<i32>,
     pub abandoned_numbers: Vec<i32>,
+    /// 1-based place among waiting numbers. None unless the user's number is waiting
+    pub position: Option<i64>,
+    pub people_ahead: Option<i64>,
+    /// Seconds until the user's number is expected to be called, from recent service times
+    pub estimated_wait_seconds: Option<i64>,
+}
+
+/// Lifecycle of a ticket in a queue.
+/// Waiting -> Called -> Serving -> Served, with Abandoned and NoShow as the other terminal states
+#[derive(Serialize, Deserialize, Hash, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TicketStatus {
+    Waiting,
+    Called,
+    Serving,
+    Served,
+    Abandoned,
+    NoShow,
+}
+
+impl TicketStatus {
+    /// Every ticket starts here, `transition` decides where it can go next
+    pub const INITIAL: TicketStatus = TicketStatus::Waiting;
+    /// Statuses of a ticket that still holds a place in the queue
+    pub const ACTIVE: [TicketStatus; 3] = [
+        TicketStatus::Waiting,
+        TicketStatus::Called,
+        TicketStatus::Serving,
+    ];
+    /// Statuses of the ticket currently at the counter. At most one ticket per queue is in one of these
+    pub const SELECTED: [TicketStatus; 2] = [TicketStatus::Called, TicketStatus::Serving];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TicketStatus::Waiting => "waiting",
+            TicketStatus::Called => "called",
+            TicketStatus::Serving => "serving",
+            TicketStatus::Served => "served",
+            TicketStatus::Abandoned => "abandoned",
+            TicketStatus::NoShow => "no_show",
+        }
+    }
+
+    /// The single place that decides which moves between statuses are legal
+    pub fn transition(self, next: TicketStatus) -> Result<TicketStatus, IllegalTransition> {
+        use TicketStatus::*;
+        match (self, next) {
+            (Waiting, Called)
+            | (Waiting, Abandoned)
+            | (Called, Serving)
+            | (Called, Served)
+            | (Called, NoShow)
+            | (Called, Abandoned)
+            | (Serving, Served) => Ok(next),
+            _ => Err(IllegalTransition {
+                from: self,
+                to: next,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for TicketStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for TicketStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "waiting" => Ok(TicketStatus::Waiting),
+            "called" => Ok(TicketStatus::Called),
+            "serving" => Ok(TicketStatus::Serving),
+            "served" => Ok(TicketStatus::Served),
+            "abandoned" => Ok(TicketStatus::Abandoned),
+            "no_show" => Ok(TicketStatus::NoShow),
+            _ => Err(format!("Unknown ticket status {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IllegalTransition {
+    pub from: TicketStatus,
+    pub to: TicketStatus,
+}
+
+impl fmt::Display for IllegalTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Ticket cannot move from {} to {}", self.from, self.to)
+    }
+}
+
+impl std::error::Error for IllegalTransition {}
+
+/// Top level routes, and the fixed segments under `/api`, `/admin` and `/public` that sit where a
+/// queue's slug goes. A queue with one of these slugs would shadow a route or be shadowed by it
+pub const RESERVED_SLUGS: [&str; 15] = [
+    "public",
+    "api",
+    "admin",
+    "remote",
+    "hello",
+    "queues",
+    "sessions",
+    "test",
+    "users",
+    "policies",
+    "get_user_info2",
+    "token_exchange",
+    "login_providers",
+    "logged_out",
+    "backchannel_logout",
+];
+const MAX_SLUG_LENGTH: usize = 32;
+const MAX_NAME_LENGTH: usize = 64;
+const MAX_DESCRIPTION_LENGTH: usize = 500;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CreateQueueRequest {
+    pub slug: String,
+    pub name: String,
+    pub description: String,
+}
+
+impl CreateQueueRequest {
+    /// Check the request before it is sent or persisted. Returns a user facing error message
+    pub fn validate(&self) -> Result<(), String> {
+        validate_slug(&self.slug)?;
+        if self.name.trim().is_empty() {
+            return Err("Name must not be empty".to_string());
+        }
+        if self.name.chars().count() > MAX_NAME_LENGTH {
+            return Err(format!(
+                "Name must be at most {} characters",
+                MAX_NAME_LENGTH
+            ));
+        }
+        if self.description.chars().count() > MAX_DESCRIPTION_LENGTH {
+            return Err(format!(
+                "Description must be at most {} characters",
+                MAX_DESCRIPTION_LENGTH
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Slugs are used as the subapp path, e.g. `/<slug>`, so they are restricted to
+/// lowercase letters, digits and dashes, and must not collide with a reserved route
+pub fn validate_slug(slug: &str) -> Result<(), String> {
+    if slug.is_empty() || slug.len() > MAX_SLUG_LENGTH {
+        return Err(format!(
+            "Slug must be between 1 and {} characters",
+            MAX_SLUG_LENGTH
+        ));
+    }
+    if !slug
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        return Err("Slug may only contain lowercase letters, digits and dashes".to_string());
+    }
+    if slug.starts_with('-') || slug.ends_with('-') {
+        return Err("Slug must not start or end with a dash".to_string());
+    }
+    if RESERVED_SLUGS.contains(&slug) {
+        return Err(format!("Slug {} is reserved", slug));
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueueDetails {
+    pub slug: String,
+    pub name: String,
+    pub description: String,
+    pub owner: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct QueueSummary {
+    pub name: String,
+    pub slug: String,
+    pub waiting_count: i64,
+    pub current_number: Option<i32>,
+}
+
+/// An identity provider users can log in with
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LoginProvider {
+    /// Used in `/public/{subapp}/trigger_login/{name}`
+    pub name: String,
+    pub display_name: String,
+}
+
+/// Header the browser adds to state-changing requests. Cross-site forms cannot set custom
+/// headers, so its presence shows the request came from our own frontend
+pub const CSRF_HEADER: &str = "X-CSRF";
+pub const CSRF_HEADER_VALUE: &str = "1";
+
+/// Machine readable reason for an API error
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiErrorCode {
+    MissingCsrfHeader,
+}
+
+/// JSON body of API errors, so the frontend can tell them apart
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiError {
+    pub code: ApiErrorCode,
+    pub message: String,
+}
+
+/// Roles granted to users through `/admin/users`
+#[derive(Serialize, Deserialize, Hash, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Admin,
+    Observer,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Observer => "observer",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "admin" => Ok(Role::Admin),
+            "observer" => Ok(Role::Observer),
+            _ => Err(format!("Unknown role {}", s)),
+        }
+    }
+}
+
+/// A user and the roles they were granted
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct UserRoles {
+    pub email: String,
+    pub roles: Vec<Role>,
+}
+
+/// Requests with a safe method like GET
+pub const READ_ACTION: &str = "read";
+/// Requests that may change something, like POST or DELETE
+pub const WRITE_ACTION: &str = "write";
+/// Actions a casbin policy can allow. `*` allows every action
+pub const POLICY_ACTIONS: [&str; 3] = [READ_ACTION, WRITE_ACTION, "*"];
+const MAX_POLICY_RULE_LENGTH: usize = 500;
+
+/// An authorisation policy: requests for `resource` doing `action` are allowed when `rule`
+/// evaluates to true for the user, e.g. `r.subject.is_logged_in == true`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Policy {
+    pub id: i32,
+    pub rule: String,
+    pub resource: String,
+    pub action: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PolicyRequest {
+    pub rule: String,
+    pub resource: String,
+    pub action: String,
+}
+
+impl PolicyRequest {
+    /// Check the shape of the policy. Returns a user facing error message
+    pub fn validate(&self) -> Result<(), String> {
+        if self.rule.trim().is_empty() {
+            return Err("Rule must not be empty".to_string());
+        }
+        if self.rule.chars().count() > MAX_POLICY_RULE_LENGTH {
+            return Err(format!(
+                "Rule must be at most {} characters",
+                MAX_POLICY_RULE_LENGTH
+            ));
+        }
+        if self.rule.contains(|c: char| c.is_control()) {
+            return Err("Rule must be a single line".to_string());
+        }
+        if !self.resource.starts_with('/') || self.resource.contains(char::is_whitespace) {
+            return Err("Resource must be a path starting with /, e.g. /api/*".to_string());
+        }
+        if !POLICY_ACTIONS.contains(&self.action.as_str()) {
+            return Err(format!(
+                "Action must be one of {}",
+                POLICY_ACTIONS.join(", ")
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Roles a user can hold within a single queue. Owners manage the queue's staff, staff serve it
+#[derive(Serialize, Deserialize, Hash, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueRole {
+    Owner,
+    Staff,
+}
+
+impl QueueRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QueueRole::Owner => "owner",
+            QueueRole::Staff => "staff",
+        }
+    }
+}
+
+impl fmt::Display for QueueRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for QueueRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "owner" => Ok(QueueRole::Owner),
+            "staff" => Ok(QueueRole::Staff),
+            _ => Err(format!("Unknown queue role {}", s)),
+        }
+    }
+}
+
+/// Someone who holds a role within a queue
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct QueueMember {
+    pub email: String,
+    pub role: QueueRole,
+}
+
+/// Returned when every session of a user is ended at once
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RevokedSessions {
+    /// How many sessions were ended
+    pub revoked: usize,
+}
+
+/// Returned by `POST /api/{subapp}/trigger_logout`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LogoutResponse {
+    /// Where to send the browser next, the queue's page or the provider's logout page
+    pub redirect_url: String,
+}
+
+/// A logged in session of the user, as listed by `/api/sessions`. Times are Unix timestamps
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SessionSummary {
+    pub id: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub expires_at: i64,
+    /// The session this request was made with
+    pub current: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::validate_slug;
+    use super::TicketStatus::{self, *};
+
+    const ALL: [TicketStatus; 6] = [Waiting, Called, Serving, Served, Abandoned, NoShow];
+    const LEGAL: [(TicketStatus, TicketStatus); 7] = [
+        (Waiting, Called),
+        (Waiting, Abandoned),
+        (Called, Serving),
+        (Called, Served),
+        (Called, NoShow),
+        (Called, Abandoned),
+        (Serving, Served),
+    ];
+
+    #[test]
+    fn route_segments_are_reserved_slugs() {
+        for slug in ["remote", "policies", "users", "sessions", "queues", "test"] {
+            assert!(validate_slug(slug).is_err(), "{}", slug);
+        }
+        assert!(validate_slug("policy-desk").is_ok());
+    }
+
+    #[test]
+    fn legal_transitions_are_allowed() {
+        for (from, to) in LEGAL {
+            assert_eq!(from.transition(to), Ok(to), "{} -> {}", from, to);
+        }
+    }
+
+    #[test]
+    fn illegal_transitions_are_rejected() {
+        for (from, to) in [(Served, Serving), (NoShow, Waiting), (Waiting, Served)] {
+            let error = from.transition(to).unwrap_err();
+            assert_eq!((error.from, error.to), (from, to));
+        }
+    }
+
+    #[test]
+    fn only_listed_transitions_are_allowed() {
+        for from in ALL {
+            for to in ALL {
+                let legal = LEGAL.contains(&(from, to));
+                assert_eq!(from.transition(to).is_ok(), legal, "{} -> {}", from, to);
+            }
+        }
+    }
 }
@@ -1,3 +1,4 @@
+use common::{CSRF_HEADER, CSRF_HEADER_VALUE};
 use gloo_console::info;
 use gloo_net::http::Request;
 use sycamore::{futures::spawn_local_scoped, prelude::*};
@@ -6,6 +7,7 @@ use crate::button::GetNumberState;
 
 #[derive(Prop)]
 pub struct AbandonConfirmationModalProps<'mainbody> {
+    subapp: String,
     should_display_abandon_modal: &'mainbody Signal<bool>,
     get_number_state: &'mainbody Signal<GetNumberState>,
     assigned_number: &'mainbody Signal<Option<i32>>,
@@ -40,6 +42,7 @@ pub fn AbandonConfirmationModal<'mainbody, G: Html>(
                             spawn_local_scoped(
                                 cx,
                                 handle_abandon_number(
+                                    props.subapp.clone(),
                                     props.should_display_abandon_modal,
                                     props.get_number_state,
                                     props.assigned_number
@@ -59,11 +62,16 @@ pub fn AbandonConfirmationModal<'mainbody, G: Html>(
 }
 
 async fn handle_abandon_number(
+    subapp: String,
     should_display_abandon_modal: &Signal<bool>,
     get_number_state: &Signal<GetNumberState>,
     assigned_number: &Signal<Option<i32>>,
 ) {
-    let _req = match Request::post("/api/abandon_assigned_number").send().await {
+    let _req = match Request::post(&format!("/api/{}/abandon_assigned_number", subapp))
+        .header(CSRF_HEADER, CSRF_HEADER_VALUE)
+        .send()
+        .await
+    {
         Ok(response) => response,
         Err(_) => {
             info!("Error when firing request to abandon endpoint");
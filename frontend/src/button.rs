@@ -1,4 +1,4 @@
-use common::UserInfo;
+use common::{ApiError, UserInfo, CSRF_HEADER, CSRF_HEADER_VALUE};
 use gloo_console::info;
 use gloo_net::http::Request;
 use sycamore::futures::*;
@@ -6,6 +6,7 @@ use sycamore::prelude::*;
 
 #[derive(Prop)]
 pub struct ButtonProps<'mainbody> {
+    subapp: String,
     should_disable_button: &'mainbody ReadSignal<bool>,
     button_text: &'mainbody ReadSignal<String>,
     get_number_state: &'mainbody Signal<GetNumberState>,
@@ -24,7 +25,7 @@ pub fn TheButton<'mainbody, G: Html>(
             class="button is-large is-light",
             disabled=*props.should_disable_button.get(),
             on:click=move|_| {
-                spawn_local_scoped(cx, handle_get_number(props.get_number_state, props.assigned_number, props.should_display_abandon_modal));
+                spawn_local_scoped(cx, handle_get_number(props.subapp.clone(), props.get_number_state, props.assigned_number, props.should_display_abandon_modal));
             }
         )
         {
@@ -44,6 +45,7 @@ pub enum GetNumberState {
 }
 
 async fn handle_get_number(
+    subapp: String,
     get_number_state: &Signal<GetNumberState>,
     assigned_number: &Signal<Option<i32>>,
     should_display_abandon_modal: &Signal<bool>,
@@ -56,7 +58,11 @@ async fn handle_get_number(
 
     // Get number flow
     (*get_number_state).set(GetNumberState::Processing);
-    let req = match Request::post("/api/get_new_number").send().await {
+    let req = match Request::post(&format!("/api/{}/get_new_number", subapp))
+        .header(CSRF_HEADER, CSRF_HEADER_VALUE)
+        .send()
+        .await
+    {
         Ok(response) => {
             info!("Done firing getting new number");
             response
@@ -69,7 +75,13 @@ async fn handle_get_number(
     };
     if !req.ok() {
         (*get_number_state).set(GetNumberState::Failed);
-        info!("Error when trying to obtain queue number. Check serverside logs")
+        match req.json::<ApiError>().await {
+            Ok(e) => info!(format!(
+                "Error when trying to obtain queue number: {}",
+                e.message
+            )),
+            Err(_) => info!("Error when trying to obtain queue number. Check serverside logs"),
+        }
     } else {
         info!("Done firing getting new number");
         if let Ok(x) = req.json::<UserInfo>().await {
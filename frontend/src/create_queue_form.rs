@@ -0,0 +1,146 @@
+use common::{CreateQueueRequest, QueueDetails, CSRF_HEADER, CSRF_HEADER_VALUE};
+use gloo_console::info;
+use gloo_net::http::Request;
+use sycamore::{futures::spawn_local_scoped, prelude::*};
+
+#[derive(Prop)]
+pub struct CreateQueueFormProps<'root> {
+    should_display_create_form: &'root Signal<bool>,
+}
+
+#[component]
+pub fn CreateQueueForm<'root, G: Html>(
+    cx: Scope<'root>,
+    props: CreateQueueFormProps<'root>,
+) -> View<G> {
+    let slug = create_signal(cx, String::new());
+    let name = create_signal(cx, String::new());
+    let description = create_signal(cx, String::new());
+    let error_message = create_signal(cx, None::<String>);
+    let is_submitting = create_signal(cx, false);
+    view! {
+        cx,
+        div(class=(
+                if *props.should_display_create_form.get() {
+                    "box"
+                } else {
+                    "box is-hidden"
+                }
+            )
+        ){
+            div(class="field"){
+                label(class="label"){"Slug"}
+                div(class="control"){
+                    input(class="input", type="text", placeholder="pharmacy", bind:value=slug)
+                }
+                p(class="help"){"Your queue will be available at /<slug>"}
+            }
+            div(class="field"){
+                label(class="label"){"Display Name"}
+                div(class="control"){
+                    input(class="input", type="text", placeholder="Pharmacy", bind:value=name)
+                }
+            }
+            div(class="field"){
+                label(class="label"){"Description"}
+                div(class="control"){
+                    textarea(class="textarea", bind:value=description)
+                }
+            }
+            (
+                if let Some(message) = (*error_message.get()).clone() {
+                    view! {cx, div(class="notification is-danger"){(message)}}
+                } else {
+                    view! {cx, }
+                }
+            )
+            div(class="field is-grouped"){
+                div(class="control"){
+                    button(
+                        class="button is-info",
+                        disabled=*is_submitting.get(),
+                        on:click=move|_| {
+                            spawn_local_scoped(
+                                cx,
+                                handle_create_queue(
+                                    CreateQueueRequest {
+                                        slug: (*slug.get()).clone(),
+                                        name: (*name.get()).clone(),
+                                        description: (*description.get()).clone(),
+                                    },
+                                    error_message,
+                                    is_submitting,
+                                )
+                            )
+                        }
+                    ){
+                        "Create"
+                    }
+                }
+                div(class="control"){
+                    button(class="button is-light", on:click=|_|(*props.should_display_create_form).set(false)){
+                        "Cancel"
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn handle_create_queue(
+    create_request: CreateQueueRequest,
+    error_message: &Signal<Option<String>>,
+    is_submitting: &Signal<bool>,
+) {
+    if let Err(message) = create_request.validate() {
+        (*error_message).set(Some(message));
+        return;
+    }
+    (*is_submitting).set(true);
+    let request = match Request::post("/api/queues")
+        .header(CSRF_HEADER, CSRF_HEADER_VALUE)
+        .json(&create_request)
+    {
+        Ok(request) => request,
+        Err(_) => {
+            info!("Error serialising CreateQueueRequest struct");
+            (*is_submitting).set(false);
+            return;
+        }
+    };
+    let resp = match request.send().await {
+        Ok(response) => response,
+        Err(_) => {
+            info!("Error when firing request to create queue endpoint");
+            (*error_message).set(Some("Failed to create queue".to_string()));
+            (*is_submitting).set(false);
+            return;
+        }
+    };
+    if !resp.ok() {
+        let message = resp
+            .text()
+            .await
+            .unwrap_or_else(|_| "Failed to create queue".to_string());
+        (*error_message).set(Some(message));
+        (*is_submitting).set(false);
+        return;
+    }
+    match resp.json::<QueueDetails>().await {
+        Ok(queue) => {
+            info!("Successfully created queue {}", &queue.slug);
+            // Full page load so that the subapp sets up its own subscriptions
+            let location = web_sys::window()
+                .expect("Expected to be running in a browser")
+                .location();
+            if location.set_href(&format!("/{}", queue.slug)).is_err() {
+                info!("Failed to redirect to new queue");
+            }
+        }
+        Err(_) => {
+            info!("Error unmarshaling QueueDetails struct");
+            (*error_message).set(Some("Failed to create queue".to_string()));
+        }
+    }
+    (*is_submitting).set(false);
+}
@@ -1,16 +1,20 @@
 mod abandon_confirmation_modal;
+mod create_queue_form;
 mod panel;
 // use gloo_console::info;
 mod button;
 mod hero;
 mod navbar;
+mod queue_directory;
 mod tiles;
 
 use crate::abandon_confirmation_modal::AbandonConfirmationModal;
 use crate::button::GetNumberState;
+use crate::create_queue_form::CreateQueueForm;
+use crate::queue_directory::QueueDirectory;
 use crate::tiles::Tiles;
 use button::TheButton;
-use common::{ServerSentData, UserInfo};
+use common::{LoginProvider, ServerSentData, UserInfo};
 use futures::stream::StreamExt;
 use gloo_console::log;
 use gloo_net::eventsource::futures::EventSource;
@@ -38,12 +42,18 @@ fn main() {
     sycamore::render(|cx| {
         let username = create_signal(cx, "anonymous".to_string());
         let is_logged_in = create_signal(cx, false);
+        let login_providers = create_signal(cx, Vec::<LoginProvider>::new());
         let get_number_state = create_signal(cx, GetNumberState::New);
         let assigned_number = create_signal(cx, None::<i32>);
         let should_display_abandon_modal = create_signal(cx, false);
+        let should_display_create_form = create_signal(cx, false);
+        let should_display_directory = create_signal(cx, false);
         let selected_number = create_signal(cx, None::<i32>);
         let abandoned_numbers = create_signal(cx, Vec::<i32>::new());
         let done_numbers = create_signal(cx, Vec::<i32>::new());
+        let position = create_signal(cx, None::<i64>);
+        let people_ahead = create_signal(cx, None::<i64>);
+        let estimated_wait_seconds = create_signal(cx, None::<i64>);
         // Derived signals
         let should_disable_button = create_memo(cx, || {
             if *is_logged_in.get() {
@@ -83,37 +93,10 @@ fn main() {
                 Err(e) => log!(format!("Failed to call get_user_info2 with error: {}", e)),
             }
         });
-
-        // TODO: Remove this if we can push from serverside
-        create_effect(cx, move || {
-            if *get_number_state.get() == GetNumberState::New {
-                spawn_local_scoped(cx, async move {
-                    let result =
-                        get_json_response::<ServerSentData>("/public/get_selected_number").await;
-                    if let Ok(data) = result {
-                        selected_number.set(data.selected_number);
-                        assigned_number.set(data.assigned_number);
-                        abandoned_numbers.set(data.abandoned_numbers);
-                        done_numbers.set(data.done_numbers);
-                    }
-                });
-            }
-        });
-        // server sent events
-        let mut es = EventSource::new("/public/subscribe").unwrap();
-        let mut server_sent_stream = es.subscribe("data").unwrap();
         spawn_local_scoped(cx, async move {
-            // weird bug, doesn't work if i don't call es.state() here
-            log!(format!("{:#?}", es.state()));
-            while let Some(Ok((_event_type, msg))) = server_sent_stream.next().await {
-                // let k = msg.data();
-                let string_data = msg.data().as_string().unwrap();
-                let data: ServerSentData = serde_json::from_str(&string_data)
-                    .expect("Expected to be able to deserialise server sent event");
-                selected_number.set(data.selected_number);
-                assigned_number.set(data.assigned_number);
-                abandoned_numbers.set(data.abandoned_numbers);
-                done_numbers.set(data.done_numbers);
+            match get_json_response::<Vec<LoginProvider>>("/public/login_providers").await {
+                Ok(x) => login_providers.set(x),
+                Err(e) => log!(format!("Failed to call login_providers with error: {}", e)),
             }
         });
 
@@ -139,10 +122,31 @@ fn main() {
                 view=move |cx, route: &ReadSignal<AppRoutes>| {
                     match route.get().as_ref() {
                         AppRoutes::SubApp(subapp) => {
+                            // server sent events. The server sends a snapshot as soon as we subscribe,
+                            // and the browser resumes from the last event id when it reconnects
+                            let mut es = EventSource::new(&format!("/public/{}/subscribe", subapp)).unwrap();
+                            let mut server_sent_stream = es.subscribe("data").unwrap();
+                            spawn_local_scoped(cx, async move {
+                                // weird bug, doesn't work if i don't call es.state() here
+                                log!(format!("{:#?}", es.state()));
+                                while let Some(Ok((_event_type, msg))) = server_sent_stream.next().await {
+                                    // let k = msg.data();
+                                    let string_data = msg.data().as_string().unwrap();
+                                    let data: ServerSentData = serde_json::from_str(&string_data)
+                                        .expect("Expected to be able to deserialise server sent event");
+                                    selected_number.set(data.selected_number);
+                                    assigned_number.set(data.assigned_number);
+                                    abandoned_numbers.set(data.abandoned_numbers);
+                                    done_numbers.set(data.done_numbers);
+                                    position.set(data.position);
+                                    people_ahead.set(data.people_ahead);
+                                    estimated_wait_seconds.set(data.estimated_wait_seconds);
+                                }
+                            });
                             view! {
                                 cx,
                                 div(class="container is-widescreen"){
-                                    NavBar(username=username, is_logged_in=is_logged_in, subapp=subapp.to_string())
+                                    NavBar(username=username, is_logged_in=is_logged_in, login_providers=login_providers, subapp=subapp.to_string())
                                     Tiles(
                                         subapp=subapp.clone(),
                                         selected_number=selected_number,
@@ -152,9 +156,13 @@ fn main() {
                                         button_text=button_text,
                                         should_display_abandon_modal=should_display_abandon_modal,
                                         abandoned_numbers=abandoned_numbers,
-                                        done_numbers=done_numbers
+                                        done_numbers=done_numbers,
+                                        position=position,
+                                        people_ahead=people_ahead,
+                                        estimated_wait_seconds=estimated_wait_seconds
                                     )
                                     AbandonConfirmationModal(
+                                        subapp=subapp.clone(),
                                         should_display_abandon_modal=should_display_abandon_modal,
                                         get_number_state=get_number_state,
                                         assigned_number=assigned_number
@@ -167,17 +175,25 @@ fn main() {
                                 cx,
                                 div(class="container is-widescreen"){
                                     // TODO: Logout broken
-                                    NavBar(username=username, is_logged_in=is_logged_in, subapp="".to_string())
+                                    NavBar(username=username, is_logged_in=is_logged_in, login_providers=login_providers, subapp="".to_string())
                                     Hero(
                                         title="Welcome to the queueing app".to_string(),
                                         subtitle="Create new app or select existing app".to_string()
                                     )
-                                    button(class="button is-info", disabled=true){"Create new"}
-                                    button(class="button is-success"){"Select existing"}
-                                    // TODO: Make this reactive. When they select existing, manifest a list of selectable apps
+                                    button(
+                                        class="button is-info",
+                                        disabled=!*is_logged_in.get(),
+                                        on:click=|_|should_display_create_form.set(true)
+                                    ){"Create new"}
+                                    button(
+                                        class="button is-success",
+                                        on:click=|_|should_display_directory.set(!*should_display_directory.get())
+                                    ){"Select existing"}
                                     div(class="block"){
                                         a(class="button", href="/demo", rel="external"){"Demo"}
                                     }
+                                    CreateQueueForm(should_display_create_form=should_display_create_form)
+                                    QueueDirectory(should_display_directory=should_display_directory)
                                 }
                             }
                         }
@@ -1,10 +1,15 @@
+use common::{LoginProvider, LogoutResponse, CSRF_HEADER, CSRF_HEADER_VALUE};
+use gloo_console::info;
+use gloo_net::http::Request;
 use sycamore::builder::prelude::*;
+use sycamore::futures::*;
 use sycamore::prelude::*;
 
 #[derive(Prop, Clone)]
 pub struct NavBarProps<'navbar> {
     username: &'navbar ReadSignal<String>,
     is_logged_in: &'navbar ReadSignal<bool>,
+    login_providers: &'navbar ReadSignal<Vec<LoginProvider>>,
     subapp: String,
 }
 
@@ -26,7 +31,6 @@ pub fn NavBar<'navbar, G: Html>(cx: Scope<'navbar>, props: NavBarProps<'navbar>)
 #[component]
 fn NavBarEndMenu<'navbar, G: Html>(cx: Scope<'navbar>, props: NavBarProps<'navbar>) -> View<G> {
     let subapp = props.subapp;
-    let login_url = format!("/public/{}/trigger_login", &subapp);
     let logout_url = format!("/api/{}/trigger_logout", &subapp);
     let root = div().class("navbar-end");
     root.dyn_if(
@@ -41,21 +45,65 @@ fn NavBarEndMenu<'navbar, G: Html>(cx: Scope<'navbar>, props: NavBarProps<'navba
                     .dyn_t(|| props.username.get().to_string()))
                 .c(div().class("navbar-dropdown").c(a()
                     .class("navbar-item")
-                    .dyn_attr("href", move || Some(logout_url.clone()))
-                    .attr("rel", "external")
+                    .attr("href", "#")
+                    .on("click", move |_| {
+                        spawn_local_scoped(cx, handle_logout(logout_url.clone()))
+                    })
                     .t("Logout")))
         },
         move || {
-            let login_url = login_url.clone();
-            a().class("button is-black")
-                .dyn_attr("href", move || Some(login_url.clone()))
-                .attr("rel", "external")
-                .t("Login")
+            // One login button per provider the server knows about
+            let buttons = props
+                .login_providers
+                .get()
+                .iter()
+                .map(|provider| {
+                    let login_url = format!("/public/{}/trigger_login/{}", &subapp, provider.name);
+                    let display_name = provider.display_name.clone();
+                    a().class("button is-black")
+                        .attr("href", login_url)
+                        .attr("rel", "external")
+                        .dyn_t(move || display_name.clone())
+                        .view(cx)
+                })
+                .collect();
+            div()
+                .class("navbar-item buttons")
+                .c(View::new_fragment(buttons))
         },
     )
     .view(cx)
 }
 
+/// Logging out changes state, so it is a POST with the anti-forgery header rather than a link
+async fn handle_logout(logout_url: String) {
+    let response = match Request::post(&logout_url)
+        .header(CSRF_HEADER, CSRF_HEADER_VALUE)
+        .send()
+        .await
+    {
+        Ok(response) => response,
+        Err(_) => {
+            info!("Error when firing request to logout endpoint");
+            return;
+        }
+    };
+    let redirect_url = match response.json::<LogoutResponse>().await {
+        Ok(logout) => logout.redirect_url,
+        Err(_) => {
+            info!("Error deserialising logout response");
+            return;
+        }
+    };
+    // Full page load, the provider's logout page may be on another site
+    let location = web_sys::window()
+        .expect("Expected to be running in a browser")
+        .location();
+    if location.set_href(&redirect_url).is_err() {
+        info!("Failed to redirect after logout");
+    }
+}
+
 // #[component]
 // fn NavBarEndMenuDsl<'navbar, G: Html>(cx: Scope<'navbar>, props: NavBarProps<'navbar>) -> View<G> {
 //     let subapp = props.subapp;
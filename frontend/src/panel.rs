@@ -34,6 +34,8 @@ pub fn Panel<'panel, G: Html>(cx: Scope<'panel>, props: PanelProps<'panel>) -> V
     }
 }
 
+// Builder api equivalent of Panel, kept for reference
+#[allow(dead_code)]
 #[component]
 pub fn PanelBuilder<'panel, G: Html>(cx: Scope<'panel>, props: PanelProps<'panel>) -> View<G> {
     let root = article()
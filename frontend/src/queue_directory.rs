@@ -0,0 +1,129 @@
+use common::QueueSummary;
+use gloo_console::log;
+use sycamore::{futures::spawn_local_scoped, prelude::*};
+
+use crate::get_json_response;
+
+const PAGE_SIZE: usize = 10;
+
+#[derive(Prop)]
+pub struct QueueDirectoryProps<'root> {
+    should_display_directory: &'root ReadSignal<bool>,
+}
+
+#[component]
+pub fn QueueDirectory<'root, G: Html>(
+    cx: Scope<'root>,
+    props: QueueDirectoryProps<'root>,
+) -> View<G> {
+    let queues = create_signal(cx, Vec::<QueueSummary>::new());
+    let search = create_signal(cx, String::new());
+    let page = create_signal(cx, 0usize);
+
+    // Only fetch the directory once it is opened
+    create_effect(cx, move || {
+        if *props.should_display_directory.get() {
+            spawn_local_scoped(cx, async move {
+                match get_json_response::<Vec<QueueSummary>>("/public/queues").await {
+                    Ok(x) => queues.set(x),
+                    Err(e) => log!(format!("Failed to call /public/queues with error: {}", e)),
+                }
+            });
+        }
+    });
+    // Go back to the first page whenever the search changes
+    create_effect(cx, move || {
+        search.track();
+        page.set(0);
+    });
+
+    let filtered_queues = create_memo(cx, || {
+        let search = search.get().trim().to_lowercase();
+        queues
+            .get()
+            .iter()
+            .filter(|queue| {
+                queue.name.to_lowercase().contains(&search) || queue.slug.contains(&search)
+            })
+            .cloned()
+            .collect::<Vec<QueueSummary>>()
+    });
+    let page_count = create_memo(cx, || {
+        filtered_queues.get().len().div_ceil(PAGE_SIZE).max(1)
+    });
+    let current_page = create_memo(cx, || {
+        filtered_queues
+            .get()
+            .iter()
+            .skip(*page.get() * PAGE_SIZE)
+            .take(PAGE_SIZE)
+            .cloned()
+            .collect::<Vec<QueueSummary>>()
+    });
+
+    view! {
+        cx,
+        article(class=(
+                if *props.should_display_directory.get() {
+                    "panel is-success"
+                } else {
+                    "panel is-success is-hidden"
+                }
+            )
+        ){
+            p(class="panel-heading"){
+                "Queues"
+            }
+            div(class="panel-block"){
+                p(class="control"){
+                    input(class="input", type="text", placeholder="Search", bind:value=search)
+                }
+            }
+            Keyed(
+                iterable=current_page,
+                view=|cx, queue| {
+                    let href = format!("/{}", queue.slug);
+                    view! {
+                        cx,
+                        a(class="panel-block", href=href, rel="external"){
+                            span(class="has-text-weight-semibold"){(queue.name)}
+                            span(class="ml-2 has-text-grey"){"/" (queue.slug)}
+                            span(class="ml-auto tag is-warning"){
+                                "Current: " (
+                                    if let Some(number) = queue.current_number {
+                                        number.to_string()
+                                    } else {
+                                        "None".to_string()
+                                    }
+                                )
+                            }
+                            span(class="ml-2 tag is-info"){
+                                (queue.waiting_count) " waiting"
+                            }
+                        }
+                    }
+                },
+                key=|queue| queue.slug.clone()
+            )
+            div(class="panel-block"){
+                button(
+                    class="button is-small",
+                    disabled=*page.get() == 0,
+                    on:click=|_| page.set(page.get().saturating_sub(1))
+                ){
+                    "Previous"
+                }
+                span(class="mx-3"){
+                    "Page " (*page.get() + 1) " of " (*page_count.get())
+                }
+                button(
+                    class="button is-small",
+                    disabled=*page.get() + 1 >= *page_count.get(),
+                    on:click=|_| page.set(*page.get() + 1)
+                ){
+                    "Next"
+                }
+            }
+        }
+    }
+}
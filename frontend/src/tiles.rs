@@ -1,6 +1,8 @@
 use crate::button::GetNumberState;
 use crate::Panel;
 use crate::TheButton;
+use gloo_timers::future::TimeoutFuture;
+use sycamore::futures::spawn_local_scoped;
 use sycamore::prelude::*;
 
 #[derive(Prop)]
@@ -14,10 +16,27 @@ pub struct TilesProps<'mainbody> {
     pub should_display_abandon_modal: &'mainbody Signal<bool>,
     pub abandoned_numbers: &'mainbody Signal<Vec<i32>>,
     pub done_numbers: &'mainbody Signal<Vec<i32>>,
+    pub position: &'mainbody Signal<Option<i64>>,
+    pub people_ahead: &'mainbody Signal<Option<i64>>,
+    pub estimated_wait_seconds: &'mainbody Signal<Option<i64>>,
 }
 
 #[component]
 pub fn Tiles<'mainbody, G: Html>(cx: Scope<'mainbody>, props: TilesProps<'mainbody>) -> View<G> {
+    let button_subapp = props.subapp.clone();
+    // Count down locally between server sent updates, restarting whenever a new estimate arrives
+    let remaining_wait_seconds = create_signal(cx, None::<i64>);
+    create_effect(cx, move || {
+        remaining_wait_seconds.set(*props.estimated_wait_seconds.get());
+    });
+    spawn_local_scoped(cx, async move {
+        loop {
+            TimeoutFuture::new(1_000).await;
+            if let Some(seconds) = *remaining_wait_seconds.get() {
+                remaining_wait_seconds.set(Some((seconds - 1).max(0)));
+            }
+        }
+    });
     view! {
         cx,
         div(class="tile is-ancestor"){
@@ -60,7 +79,26 @@ pub fn Tiles<'mainbody, G: Html>(cx: Scope<'mainbody>, props: TilesProps<'mainbo
                     p(class="subtitle"){
                         "Your Number"
                     }
+                    p(class="block"){(
+                        match (*props.position.get(), *props.people_ahead.get()) {
+                            (Some(position), Some(0)) => format!("Position {}: you're next", position),
+                            (Some(position), Some(people_ahead)) => {
+                                format!("Position {}: {} ahead of you", position, people_ahead)
+                            }
+                            _ => "".to_string(),
+                        }
+                    )}
+                    p(class="block"){(
+                        match *remaining_wait_seconds.get() {
+                            Some(0) => "Estimated wait: any moment now".to_string(),
+                            Some(seconds) => {
+                                format!("Estimated wait: {}:{:02}", seconds / 60, seconds % 60)
+                            }
+                            None => "".to_string(),
+                        }
+                    )}
                     TheButton(
+                        subapp=button_subapp,
                         should_disable_button=props.should_disable_button,
                         button_text=props.button_text,
                         get_number_state=props.get_number_state,
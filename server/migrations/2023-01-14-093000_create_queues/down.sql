@@ -0,0 +1,29 @@
+-- This file should undo anything in `up.sql`
+CREATE TABLE queue (
+    id INTEGER NOT NULL PRIMARY KEY,
+    user TEXT NOT NULL, 
+    is_selected BOOLEAN NOT NULL,
+    is_processed BOOLEAN NOT NULL,
+    is_abandoned BOOLEAN NOT NULL,
+    updated_at TIMESTAMP NOT NULL
+);
+
+INSERT INTO queue (id, user, is_selected, is_processed, is_abandoned, updated_at)
+SELECT id, user, is_selected, is_processed, is_abandoned, updated_at
+FROM tickets;
+
+CREATE INDEX idx_is_selected ON queue(is_selected);
+
+CREATE INDEX idx_is_processed on queue(is_processed);
+
+CREATE INDEX idx_assigned_number ON queue(user, is_processed, is_abandoned);
+
+DROP INDEX idx_tickets_assigned_number;
+
+DROP INDEX idx_tickets_is_processed;
+
+DROP INDEX idx_tickets_is_selected;
+
+DROP TABLE tickets;
+
+DROP TABLE queues;
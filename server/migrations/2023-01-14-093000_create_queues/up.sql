@@ -0,0 +1,38 @@
+-- Your SQL goes here
+CREATE TABLE queues (
+    id INTEGER NOT NULL PRIMARY KEY,
+    slug TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
+
+INSERT INTO queues (slug, name, created_at) VALUES ('demo', 'Demo', CURRENT_TIMESTAMP);
+
+CREATE TABLE tickets (
+    id INTEGER NOT NULL PRIMARY KEY,
+    queue_id INTEGER NOT NULL REFERENCES queues(id),
+    user TEXT NOT NULL,
+    is_selected BOOLEAN NOT NULL,
+    is_processed BOOLEAN NOT NULL,
+    is_abandoned BOOLEAN NOT NULL,
+    updated_at TIMESTAMP NOT NULL
+);
+
+-- Existing tickets all belonged to the single global queue
+INSERT INTO tickets (id, queue_id, user, is_selected, is_processed, is_abandoned, updated_at)
+SELECT id, (SELECT id FROM queues WHERE slug = 'demo'), user, is_selected, is_processed, is_abandoned, updated_at
+FROM queue;
+
+DROP INDEX idx_assigned_number;
+
+DROP INDEX idx_is_selected;
+
+DROP INDEX idx_is_processed;
+
+DROP TABLE queue;
+
+CREATE INDEX idx_tickets_is_selected ON tickets(queue_id, is_selected);
+
+CREATE INDEX idx_tickets_is_processed ON tickets(queue_id, is_processed);
+
+CREATE INDEX idx_tickets_assigned_number ON tickets(queue_id, user, is_processed, is_abandoned);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE queues DROP COLUMN owner;
+
+ALTER TABLE queues DROP COLUMN description;
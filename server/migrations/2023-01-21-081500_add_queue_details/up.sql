@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE queues ADD COLUMN description TEXT NOT NULL DEFAULT '';
+
+ALTER TABLE queues ADD COLUMN owner TEXT;
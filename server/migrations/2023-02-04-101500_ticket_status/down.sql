@@ -0,0 +1,32 @@
+-- This file should undo anything in `up.sql`
+CREATE TABLE tickets_with_flags (
+    id INTEGER NOT NULL PRIMARY KEY,
+    queue_id INTEGER NOT NULL REFERENCES queues(id),
+    user TEXT NOT NULL,
+    is_selected BOOLEAN NOT NULL,
+    is_processed BOOLEAN NOT NULL,
+    is_abandoned BOOLEAN NOT NULL,
+    updated_at TIMESTAMP NOT NULL
+);
+
+INSERT INTO tickets_with_flags (id, queue_id, user, is_selected, is_processed, is_abandoned, updated_at)
+SELECT id, queue_id, user,
+    status IN ('called', 'serving'),
+    status = 'served',
+    status IN ('abandoned', 'no_show'),
+    updated_at
+FROM tickets;
+
+DROP INDEX idx_tickets_assigned_number;
+
+DROP INDEX idx_tickets_status;
+
+DROP TABLE tickets;
+
+ALTER TABLE tickets_with_flags RENAME TO tickets;
+
+CREATE INDEX idx_tickets_is_selected ON tickets(queue_id, is_selected);
+
+CREATE INDEX idx_tickets_is_processed ON tickets(queue_id, is_processed);
+
+CREATE INDEX idx_tickets_assigned_number ON tickets(queue_id, user, is_processed, is_abandoned);
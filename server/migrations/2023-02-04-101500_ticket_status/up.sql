@@ -0,0 +1,33 @@
+-- Your SQL goes here
+CREATE TABLE tickets_with_status (
+    id INTEGER NOT NULL PRIMARY KEY,
+    queue_id INTEGER NOT NULL REFERENCES queues(id),
+    user TEXT NOT NULL,
+    status TEXT NOT NULL CHECK (status IN ('waiting', 'called', 'serving', 'served', 'abandoned', 'no_show')),
+    updated_at TIMESTAMP NOT NULL
+);
+
+INSERT INTO tickets_with_status (id, queue_id, user, status, updated_at)
+SELECT id, queue_id, user,
+    CASE
+        WHEN is_abandoned THEN 'abandoned'
+        WHEN is_processed THEN 'served'
+        WHEN is_selected THEN 'called'
+        ELSE 'waiting'
+    END,
+    updated_at
+FROM tickets;
+
+DROP INDEX idx_tickets_assigned_number;
+
+DROP INDEX idx_tickets_is_processed;
+
+DROP INDEX idx_tickets_is_selected;
+
+DROP TABLE tickets;
+
+ALTER TABLE tickets_with_status RENAME TO tickets;
+
+CREATE INDEX idx_tickets_status ON tickets(queue_id, status);
+
+CREATE INDEX idx_tickets_assigned_number ON tickets(queue_id, user, status);
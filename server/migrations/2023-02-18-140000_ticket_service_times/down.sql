@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX idx_tickets_served_at;
+
+ALTER TABLE tickets DROP COLUMN served_at;
+
+ALTER TABLE tickets DROP COLUMN called_at;
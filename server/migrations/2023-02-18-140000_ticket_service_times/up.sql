@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE tickets ADD COLUMN called_at TIMESTAMP;
+
+ALTER TABLE tickets ADD COLUMN served_at TIMESTAMP;
+
+CREATE INDEX idx_tickets_served_at ON tickets(queue_id, served_at);
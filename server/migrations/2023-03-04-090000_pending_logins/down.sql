@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX idx_pending_logins_created_at;
+
+DROP TABLE pending_logins;
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE pending_logins (
+    login_key TEXT PRIMARY KEY NOT NULL,
+    csrf_token TEXT NOT NULL,
+    nonce TEXT NOT NULL,
+    pkce_verifier TEXT NOT NULL,
+    subapp TEXT NOT NULL,
+    provider TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX idx_pending_logins_created_at ON pending_logins(created_at);
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+CREATE TABLE user_tokens (
+    session_id TEXT PRIMARY KEY NOT NULL,
+    email TEXT NOT NULL,
+    provider TEXT NOT NULL,
+    access_token TEXT NOT NULL,
+    refresh_token TEXT,
+    expires_at TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX idx_user_roles_role;
+
+DROP TABLE user_roles;
+
+DROP TABLE users;
@@ -0,0 +1,15 @@
+-- Your SQL goes here
+CREATE TABLE users (
+    email TEXT PRIMARY KEY NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
+
+CREATE TABLE user_roles (
+    email TEXT NOT NULL REFERENCES users(email) ON DELETE CASCADE,
+    role TEXT NOT NULL,
+    granted_by TEXT NOT NULL,
+    granted_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (email, role)
+);
+
+CREATE INDEX idx_user_roles_role ON user_roles(role);
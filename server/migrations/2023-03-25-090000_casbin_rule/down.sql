@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX idx_casbin_rule_unique;
+
+DROP TABLE casbin_rule;
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE casbin_rule (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    ptype TEXT NOT NULL,
+    v0 TEXT NOT NULL DEFAULT '',
+    v1 TEXT NOT NULL DEFAULT '',
+    v2 TEXT NOT NULL DEFAULT '',
+    v3 TEXT NOT NULL DEFAULT '',
+    v4 TEXT NOT NULL DEFAULT '',
+    v5 TEXT NOT NULL DEFAULT ''
+);
+
+CREATE UNIQUE INDEX idx_casbin_rule_unique ON casbin_rule(ptype, v0, v1, v2, v3, v4, v5);
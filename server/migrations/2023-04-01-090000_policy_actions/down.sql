@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM casbin_rule WHERE ptype = 'p' AND v0 LIKE '%r.subject.is_observer%';
+
+DELETE FROM casbin_rule WHERE ptype = 'p' AND v2 = 'write';
+
+UPDATE OR IGNORE casbin_rule SET v2 = 'read' WHERE ptype = 'p' AND v2 = '*';
+
+DELETE FROM casbin_rule WHERE ptype = 'p' AND v2 = '*';
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+-- Every request used to be checked as a read. Logged in users and admins keep all the access they
+-- had, as in authz/abac_policy.csv, while /public/* stays read only
+INSERT OR IGNORE INTO casbin_rule (ptype, v0, v1, v2, v3, v4, v5)
+SELECT ptype, v0, v1, 'write', v3, v4, v5 FROM casbin_rule
+WHERE ptype = 'p' AND v2 = 'read' AND v1 LIKE '/api/%';
+
+UPDATE OR IGNORE casbin_rule SET v2 = '*'
+WHERE ptype = 'p' AND v2 = 'read' AND v1 LIKE '/admin/%';
+
+-- Fresh databases get this from authz/abac_policy.csv on startup instead
+INSERT OR IGNORE INTO casbin_rule (ptype, v0, v1, v2)
+SELECT 'p', '(r.subject.is_observer == true && r.subject.is_logged_in == true)', '/admin/*', 'read'
+WHERE EXISTS (SELECT 1 FROM casbin_rule WHERE ptype = 'p');
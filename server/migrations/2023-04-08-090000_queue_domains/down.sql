@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM casbin_rule WHERE ptype = 'g';
+
+DELETE FROM casbin_rule WHERE ptype = 'p' AND v1 LIKE '/admin/:subapp/%';
@@ -0,0 +1,21 @@
+-- Your SQL goes here
+-- Fresh databases get these from authz/abac_policy.csv on startup instead
+INSERT OR IGNORE INTO casbin_rule (ptype, v0, v1, v2)
+SELECT 'p', rule, resource, action FROM (
+    SELECT 'r.subject.is_logged_in == true && (g(r.subject.email, "owner", r.domain) || g(r.subject.email, "staff", r.domain))' AS rule, '/admin/:subapp/call_next' AS resource, 'write' AS action
+    UNION ALL
+    SELECT 'r.subject.is_logged_in == true && (g(r.subject.email, "owner", r.domain) || g(r.subject.email, "staff", r.domain))', '/admin/:subapp/start_serving', 'write'
+    UNION ALL
+    SELECT 'r.subject.is_logged_in == true && (g(r.subject.email, "owner", r.domain) || g(r.subject.email, "staff", r.domain))', '/admin/:subapp/mark_served', 'write'
+    UNION ALL
+    SELECT 'r.subject.is_logged_in == true && (g(r.subject.email, "owner", r.domain) || g(r.subject.email, "staff", r.domain))', '/admin/:subapp/mark_no_show', 'write'
+    UNION ALL
+    SELECT 'r.subject.is_logged_in == true && g(r.subject.email, "owner", r.domain)', '/admin/:subapp/staff', '*'
+    UNION ALL
+    SELECT 'r.subject.is_logged_in == true && g(r.subject.email, "owner", r.domain)', '/admin/:subapp/staff/:email', '*'
+) AS queue_policies
+WHERE EXISTS (SELECT 1 FROM casbin_rule WHERE ptype = 'p');
+
+-- Queue creators own their queue's domain
+INSERT OR IGNORE INTO casbin_rule (ptype, v0, v1, v2)
+SELECT 'g', lower(owner), 'owner', slug FROM queues WHERE owner IS NOT NULL;
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE sessions (
+    session_key TEXT PRIMARY KEY NOT NULL,
+    email TEXT,
+    session_id TEXT,
+    state TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    updated_at TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX idx_sessions_email ON sessions(email);
+CREATE INDEX idx_sessions_expires_at ON sessions(expires_at);
@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM casbin_rule WHERE ptype = 'p' AND v1 = '/public/backchannel_logout/:provider';
+
+DROP INDEX idx_user_tokens_sid;
+DROP INDEX idx_user_tokens_subject;
+
+ALTER TABLE user_tokens DROP COLUMN sid;
+ALTER TABLE user_tokens DROP COLUMN subject;
+ALTER TABLE user_tokens DROP COLUMN id_token;
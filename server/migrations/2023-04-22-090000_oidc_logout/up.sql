@@ -0,0 +1,13 @@
+-- Your SQL goes here
+-- What the provider identifies the login by, so back-channel logouts can find the session
+ALTER TABLE user_tokens ADD COLUMN id_token TEXT;
+ALTER TABLE user_tokens ADD COLUMN subject TEXT;
+ALTER TABLE user_tokens ADD COLUMN sid TEXT;
+
+CREATE INDEX idx_user_tokens_subject ON user_tokens(provider, subject);
+CREATE INDEX idx_user_tokens_sid ON user_tokens(provider, sid);
+
+-- Fresh databases get this from authz/abac_policy.csv on startup instead
+INSERT OR IGNORE INTO casbin_rule (ptype, v0, v1, v2)
+SELECT 'p', 'r.subject.is_logged_in == true || r.subject.is_logged_in == false', '/public/backchannel_logout/:provider', 'write'
+WHERE EXISTS (SELECT 1 FROM casbin_rule WHERE ptype = 'p');
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+-- Concurrent joins could leave a user with several active tickets. Keep the earliest, which holds their place
+UPDATE tickets SET status = 'abandoned'
+WHERE status IN ('waiting', 'called', 'serving')
+AND EXISTS (
+    SELECT 1 FROM tickets AS earlier
+    WHERE earlier.queue_id = tickets.queue_id
+    AND earlier.user = tickets.user
+    AND earlier.status IN ('waiting', 'called', 'serving')
+    AND earlier.id < tickets.id
+);
+
+CREATE UNIQUE INDEX idx_tickets_one_active ON tickets(queue_id, user)
+WHERE status IN ('waiting', 'called', 'serving');
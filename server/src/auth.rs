@@ -1,19 +1,39 @@
-use crate::{AppState, ANONYMOUS};
+use crate::{
+    config::OidcProviderConfig,
+    database,
+    oidc::OidcClients,
+    pending_logins::PendingLogin,
+    roles::{normalise_email, RoleCache},
+    tokens::{TokenSubject, SESSION_ID_KEY},
+    AppState, ANONYMOUS,
+};
 use actix_session::Session;
 use actix_web::error::{ErrorForbidden, ErrorInternalServerError};
-use actix_web::{web, HttpRequest, HttpResponse, Result as ActixResult};
+use actix_web::{
+    dev::Payload, http::Method, web, FromRequest, HttpMessage, HttpRequest, HttpResponse,
+    Result as ActixResult,
+};
 use anyhow::{anyhow, Context, Result as AnyhowResult};
-use casbin::prelude::*;
-use casbin::Enforcer;
-use common::UserInfo;
+use common::{Role, UserInfo, READ_ACTION, WRITE_ACTION};
+use log::{info, warn};
 use openidconnect::AuthorizationCode;
 use openidconnect::{
-    core::{CoreClient, CoreProviderMetadata, CoreResponseType},
+    core::{CoreClient, CoreIdToken, CoreIdTokenClaims, CoreResponseType},
     reqwest::async_http_client as http_client,
     url::Url,
-    AuthenticationFlow, ClientId, ClientSecret, CsrfToken, IssuerUrl, Nonce, RedirectUrl, Scope,
+    AuthenticationFlow, ClaimsVerificationError, CsrfToken, Nonce, PkceCodeChallenge,
+    PkceCodeVerifier, SignatureVerificationError,
 };
 use serde::Deserialize;
+use serde_json::Value;
+use std::future::{ready, Ready};
+use std::ops::Deref;
+use uuid::Uuid;
+
+/// Session key holding the roles taken from the ID token at login
+const ROLES_KEY: &str = "roles";
+/// Bounds the session state stored for each login, whatever the provider puts in the ID token
+const MAX_ROLES: usize = 50;
 
 fn get_user_from_session_cookie(session: &Session) -> AnyhowResult<Option<String>> {
     if let Some(email) = session.get::<String>("user")? {
@@ -27,46 +47,52 @@ fn get_user_from_session_cookie(session: &Session) -> AnyhowResult<Option<String
     }
 }
 
-/// Get user info struct from cookie
-pub fn get_userinfo_from_session_cookie(session: &Session) -> AnyhowResult<UserInfo> {
+/// Get user info struct from cookie, with the user's roles from the database
+pub fn get_userinfo_from_session_cookie(
+    session: &Session,
+    role_cache: &RoleCache,
+) -> AnyhowResult<UserInfo> {
     let user = get_user_from_session_cookie(session)?;
     if let Some(email) = user {
-        let is_admin = is_admin(&email);
+        let user_roles = role_cache.get_roles(&email)?;
+        let roles = session.get::<Vec<String>>(ROLES_KEY)?.unwrap_or_default();
         Ok(UserInfo {
             email,
             is_logged_in: true,
-            is_admin,
+            is_admin: user_roles.contains(&Role::Admin),
+            is_observer: user_roles.contains(&Role::Observer),
             assigned_number: None,
+            roles,
         })
     } else {
         Ok(UserInfo {
             email: ANONYMOUS.to_string(),
             is_logged_in: false,
             is_admin: false,
+            is_observer: false,
             assigned_number: None,
+            roles: Vec::new(),
         })
     }
 }
 
-/// static list of admin emails
-fn is_admin(email: &str) -> bool {
-    let admins = vec!["jlloh89@gmail.com"];
-    admins.into_iter().filter(|x| *x == email).count() > 0
-}
-
 /// use casbin-rs to check if authorised to perform action on a given resource
 pub fn is_authorised(
     session: &Session,
-    authz_enforcer: &Enforcer,
-    request: HttpRequest,
+    app_state: &AppState,
+    domain: &str,
+    resource: &str,
+    method: &Method,
 ) -> ActixResult<UserInfo> {
-    let user_info = match get_userinfo_from_session_cookie(session) {
+    let user_info = match get_userinfo_from_session_cookie(session, &app_state.role_cache) {
         Ok(inside) => inside,
         Err(e) => return Err(ErrorInternalServerError(e.to_string())),
     };
-    let resource = request.path();
-    let action = "read";
-    match authz_enforcer.enforce((&user_info, resource, action)) {
+    let action = get_action(method);
+    match app_state
+        .authz_enforcer
+        .enforce(&user_info, domain, resource, action)
+    {
         Ok(allowed) => {
             if allowed {
                 Ok(user_info)
@@ -79,15 +105,85 @@ pub fn is_authorised(
     }
 }
 
+/// The user the authorisation middleware resolved for this request. Only available on routes
+/// the middleware checked, which is every route but the static frontend
+pub struct AuthenticatedUser(UserInfo);
+
+impl AuthenticatedUser {
+    pub fn into_inner(self) -> UserInfo {
+        self.0
+    }
+}
+
+impl Deref for AuthenticatedUser {
+    type Target = UserInfo;
+
+    fn deref(&self) -> &UserInfo {
+        &self.0
+    }
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let user_info = request.extensions().get::<UserInfo>().cloned();
+        ready(match user_info {
+            Some(user_info) => Ok(AuthenticatedUser(user_info)),
+            None => Err(ErrorInternalServerError("Request was not authorised")),
+        })
+    }
+}
+
+/// Safe methods only read, every other method may write
+pub fn get_action(method: &Method) -> &'static str {
+    if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+        READ_ACTION
+    } else {
+        WRITE_ACTION
+    }
+}
+
 #[derive(Deserialize)]
 pub struct Callback {
     pub code: String,
     pub state: String,
 }
 
+/// Check the ID token's signature and claims. A token signed with an unknown key makes us fetch
+/// the provider's keys again, unless they were fetched moments ago
+async fn verify_id_token(
+    oidc_clients: &OidcClients,
+    provider_config: &OidcProviderConfig,
+    client: &CoreClient,
+    id_token: &CoreIdToken,
+    nonce: &Nonce,
+) -> AnyhowResult<CoreIdTokenClaims> {
+    let claims = match id_token.claims(&client.id_token_verifier(), nonce) {
+        // The provider probably rotated its signing keys, so fetch them again and retry once
+        Err(ClaimsVerificationError::SignatureVerification(
+            SignatureVerificationError::NoMatchingKey,
+        )) => match oidc_clients.refresh_keys(provider_config).await? {
+            Some(discovered) => {
+                info!(
+                    "Unknown key id in id token, refreshed provider {}",
+                    provider_config.name
+                );
+                id_token
+                    .claims(&discovered.client.id_token_verifier(), nonce)
+                    .cloned()
+            }
+            None => Err(ClaimsVerificationError::SignatureVerification(
+                SignatureVerificationError::NoMatchingKey,
+            )),
+        },
+        result => result.cloned(),
+    };
+    claims.context("Failed to verify id token claims")
+}
+
 pub async fn token_exchange_internal(
-    client_id: String,
-    client_secret: String,
     req_body: web::Query<Callback>,
     app_state: web::Data<AppState>,
     session: Session,
@@ -101,63 +197,89 @@ pub async fn token_exchange_internal(
     }?;
     let returned_code = AuthorizationCode::new(req_body.code.to_owned());
     let returned_state = CsrfToken::new(req_body.state.to_owned());
-    // Retrieved stored nonce and csrf token
-    let mut session_oidc_state = app_state.session_oidc_state.lock().await;
-    // .expect("Expected to be able to lock mutex");
-    let (stored_csrf, stored_nonce, subapp) =
-        if let Some(oidc_metadata) = session_oidc_state.get(&anonuserid) {
-            Ok((
-                &oidc_metadata.csrf_token,
-                &oidc_metadata.nonce,
-                &oidc_metadata.subapp,
-            ))
-        } else {
-            Err(anyhow!("State store did not have necessary info",))
-        }?;
+    // Retrieve stored nonce and csrf token. They can only be used once
+    let PendingLogin {
+        csrf_token: stored_csrf,
+        nonce: stored_nonce,
+        pkce_verifier: stored_pkce_verifier,
+        subapp,
+        provider,
+        ..
+    } = if let Some(pending_login) = app_state.pending_logins.take(&anonuserid)? {
+        Ok(pending_login)
+    } else {
+        Err(anyhow!(
+            "Login expired or was never started. Please log in again"
+        ))
+    }?;
     // Verify csrf_state
     if returned_state.secret() != stored_csrf.secret() {
         return Err(anyhow!("Failed to verify csrf"));
     }
-    // Exchange for a token
-    // Need another client here. Copy pasta for now
-    let oidc_metadata = get_oidc_metadata(client_id, client_secret).await;
-    let provider_metadata =
-        CoreProviderMetadata::discover_async(oidc_metadata.issuer_url, http_client)
-            .await
-            .unwrap();
-    let client = CoreClient::from_provider_metadata(
-        provider_metadata,
-        oidc_metadata.client_id,
-        Some(oidc_metadata.client_secret),
-    )
-    .set_redirect_uri(RedirectUrl::new(oidc_metadata.redirect_url).expect("Invalid redirect URL"));
-    // Do token exchange
+    // Exchange for a token with the provider that started the flow, so the id token is checked
+    // against that issuer
+    let provider_config = app_state
+        .oidc_config
+        .provider(&provider)
+        .ok_or_else(|| anyhow!("Login was started with unknown provider {}", provider))?;
+    let client = app_state.oidc_clients.get(provider_config).await?;
+    // Do token exchange, proving we are the ones who started the flow
     let token_response = client
         .exchange_code(returned_code)
+        .set_pkce_verifier(stored_pkce_verifier)
         .request_async(http_client)
         .await
         .context("Failed to exchange token")?;
-    let id_token_verifier = client.id_token_verifier();
     let id_token = if let Some(id_token) = token_response.extra_fields().id_token() {
         Ok(id_token)
     } else {
         Err(anyhow!("Empty id token"))
     }?;
 
-    let id_token_claims = id_token
-        .claims(&id_token_verifier, stored_nonce)
-        .context("Failed to verify id token claims")?;
+    let id_token_claims = verify_id_token(
+        &app_state.oidc_clients,
+        provider_config,
+        &client,
+        id_token,
+        &stored_nonce,
+    )
+    .await?;
     let email = if let Some(email) = id_token_claims.email() {
         Ok(email)
     } else {
         Err(anyhow!("No email found in claims"))
     }?;
+    let raw_claims = get_raw_claims(id_token)?;
+    let roles = get_role_claims(&raw_claims, &provider_config.role_claims);
 
     let redirect_url = format!("/{}", subapp);
-    // clean up both cookie and internal state
-    (*session_oidc_state).remove(&anonuserid);
+    {
+        let db_connection = &mut app_state.db_connection_pool.get()?;
+        database::insert_user_if_missing(db_connection, &normalise_email(email.as_str()))?;
+    }
+    // Keep the tokens server side, the cookie only gets an id to find them by
+    let session_id = Uuid::new_v4().to_string();
+    let token_subject = TokenSubject {
+        email: email.to_string(),
+        provider,
+        subject: Some(id_token_claims.subject().to_string()),
+        // Not a standard ID token claim, but back-channel logouts may refer to it
+        sid: raw_claims
+            .get("sid")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+    };
+    app_state
+        .token_store
+        .save(&session_id, &token_subject, &token_response)?;
+
+    // clean up the cookie, the pending login is already gone. A new session key means nobody who
+    // knew the anonymous one can ride along on the login
     session.clear();
-    session.insert("user", email)?;
+    session.renew();
+    session.insert("user", normalise_email(email.as_str()))?;
+    session.insert(SESSION_ID_KEY, session_id)?;
+    session.insert(ROLES_KEY, roles)?;
     // session.remove(anonuser);
 
     // redirect
@@ -166,52 +288,165 @@ pub async fn token_exchange_internal(
         .body("Redirecting to login"))
 }
 
-struct OidcMetadata {
-    client_id: ClientId,
-    client_secret: ClientSecret,
-    issuer_url: IssuerUrl,
-    redirect_url: String,
+/// Every claim of the ID token. The typed claims drop everything non-standard, so this reads the
+/// payload itself. The ID token's signature must already be verified
+fn get_raw_claims(id_token: &CoreIdToken) -> AnyhowResult<serde_json::Map<String, Value>> {
+    let id_token = id_token.to_string();
+    let payload = id_token
+        .split('.')
+        .nth(1)
+        .ok_or_else(|| anyhow!("Malformed id token"))?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+        .context("Malformed id token payload")?;
+    serde_json::from_slice::<serde_json::Map<String, Value>>(&payload)
+        .context("Malformed id token claims")
 }
 
-async fn get_oidc_metadata(client_id: String, client_secret: String) -> OidcMetadata {
-    let client_id = ClientId::new(client_id);
-    let client_secret = ClientSecret::new(client_secret);
-    let issuer_url =
-        IssuerUrl::new("https://accounts.google.com".to_string()).expect("Invalid issuer URL");
-    let redirect_url = "http://localhost:8080/public/token_exchange".to_string();
-
-    OidcMetadata {
-        client_id,
-        client_secret,
-        issuer_url,
-        redirect_url,
+/// Values of the configured role claims. Claims may hold a single string or a list of strings
+fn get_role_claims(claims: &serde_json::Map<String, Value>, role_claims: &[String]) -> Vec<String> {
+    let mut roles = Vec::new();
+    for claim in role_claims {
+        match claims.get(claim) {
+            Some(Value::String(role)) => roles.push(role.clone()),
+            Some(Value::Array(values)) => {
+                for value in values {
+                    match value {
+                        Value::String(role) => roles.push(role.clone()),
+                        _ => warn!("Ignoring non-string value in claim {}", claim),
+                    }
+                }
+            }
+            Some(_) => warn!("Ignoring claim {}, it is not a string or list", claim),
+            None => {}
+        }
+    }
+    roles.sort();
+    roles.dedup();
+    if roles.len() > MAX_ROLES {
+        warn!(
+            "Keeping only {} of {} roles from the id token",
+            MAX_ROLES,
+            roles.len()
+        );
+        roles.truncate(MAX_ROLES);
     }
+    roles
 }
 
-pub async fn get_oidc_login(client_id: String, client_secret: String) -> (Url, CsrfToken, Nonce) {
-    let oidc_metadata = get_oidc_metadata(client_id, client_secret).await;
-    // Set up the config for the Google OAuth2 process.
-    let provider_metadata =
-        CoreProviderMetadata::discover_async(oidc_metadata.issuer_url, http_client)
-            .await
-            .unwrap();
-
-    let client = CoreClient::from_provider_metadata(
-        provider_metadata,
-        oidc_metadata.client_id,
-        Some(oidc_metadata.client_secret),
-    )
-    .set_redirect_uri(RedirectUrl::new(oidc_metadata.redirect_url).expect("Invalid redirect URL"));
+pub async fn get_oidc_login(
+    oidc_clients: &OidcClients,
+    oidc_config: &OidcProviderConfig,
+) -> AnyhowResult<(Url, CsrfToken, Nonce, PkceCodeVerifier)> {
+    let client = oidc_clients.get(oidc_config).await?;
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
 
-    let (authorize_url, csrf_state, nonce) = client
+    let mut authorization_request = client
         .authorize_url(
             AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
             CsrfToken::new_random,
             Nonce::new_random,
         )
-        .add_scope(Scope::new("email".to_string()))
-        .add_scope(Scope::new("profile".to_string()))
-        .url();
+        .set_pkce_challenge(pkce_challenge);
+    for scope in &oidc_config.scopes {
+        authorization_request = authorization_request.add_scope(scope.clone());
+    }
+    let (authorize_url, csrf_state, nonce) = authorization_request.url();
+
+    Ok((authorize_url, csrf_state, nonce, pkce_verifier))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oidc::ProviderUnavailable;
+    use crate::test_support::signing_key;
+    use chrono::{Duration, Utc};
+    use openidconnect::{
+        core::{CoreJsonWebKeySet, CoreJwsSigningAlgorithm},
+        Audience, EmptyAdditionalClaims, EndUserEmail, PrivateSigningKey, StandardClaims,
+        SubjectIdentifier,
+    };
+
+    /// Nothing listens here, so discovering the provider fails straight away
+    const UNREACHABLE_ISSUER: &str = "http://127.0.0.1:9";
+    const CLIENT_ID: &str = "queue-app";
+
+    fn provider() -> OidcProviderConfig {
+        OidcProviderConfig::new(
+            "test",
+            "Test".to_string(),
+            UNREACHABLE_ISSUER,
+            CLIENT_ID.to_string(),
+            "secret".to_string(),
+            "email",
+            "http://localhost:8080",
+        )
+        .unwrap()
+    }
 
-    (authorize_url, csrf_state, nonce)
+    /// Clients that know the provider, with `k1` as its only signing key
+    fn oidc_clients() -> OidcClients {
+        let oidc_clients = OidcClients::default();
+        let jwks = CoreJsonWebKeySet::new(vec![signing_key("k1").as_verification_key()]);
+        oidc_clients.insert_discovered(&provider(), jwks);
+        oidc_clients
+    }
+
+    fn nonce() -> Nonce {
+        Nonce::new("nonce".to_string())
+    }
+
+    /// An ID token for user@example.com, signed with the key `kid`
+    fn id_token(kid: &str) -> CoreIdToken {
+        let claims = CoreIdTokenClaims::new(
+            provider().issuer_url,
+            vec![Audience::new(CLIENT_ID.to_string())],
+            Utc::now() + Duration::minutes(5),
+            Utc::now(),
+            StandardClaims::new(SubjectIdentifier::new("user-1".to_string()))
+                .set_email(Some(EndUserEmail::new("user@example.com".to_string()))),
+            EmptyAdditionalClaims {},
+        )
+        .set_nonce(Some(nonce()));
+        CoreIdToken::new(
+            claims,
+            &signing_key(kid),
+            CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
+            None,
+            None,
+        )
+        .unwrap()
+    }
+
+    async fn verify(oidc_clients: &OidcClients, kid: &str) -> AnyhowResult<CoreIdTokenClaims> {
+        let client = oidc_clients.get(&provider()).await?;
+        verify_id_token(oidc_clients, &provider(), &client, &id_token(kid), &nonce()).await
+    }
+
+    #[actix_web::test]
+    async fn accepts_a_token_signed_with_a_known_key() {
+        let claims = verify(&oidc_clients(), "k1").await.unwrap();
+        assert_eq!(claims.email().unwrap().as_str(), "user@example.com");
+    }
+
+    #[actix_web::test]
+    async fn unknown_keys_do_not_rediscover_a_fresh_provider() {
+        // Rediscovering would fail, as the issuer is unreachable
+        let error = verify(&oidc_clients(), "k2").await.unwrap_err();
+        assert!(error.downcast_ref::<ProviderUnavailable>().is_none());
+        assert!(matches!(
+            error.downcast_ref::<ClaimsVerificationError>(),
+            Some(ClaimsVerificationError::SignatureVerification(
+                SignatureVerificationError::NoMatchingKey
+            ))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn unknown_keys_report_an_unavailable_provider() {
+        let oidc_clients = oidc_clients();
+        oidc_clients.forget_discovery_time(&provider());
+        let error = verify(&oidc_clients, "k2").await.unwrap_err();
+        assert!(error.downcast_ref::<ProviderUnavailable>().is_some());
+    }
 }
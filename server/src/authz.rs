@@ -0,0 +1,604 @@
+use crate::database::{self, InsertPolicy, PolicyRow};
+use crate::roles::normalise_email;
+use crate::ANONYMOUS;
+use anyhow::{anyhow, Context, Result as AnyhowResult};
+use async_trait::async_trait;
+use casbin::error::AdapterError;
+use casbin::prelude::*;
+use casbin::Adapter;
+use common::{Policy, PolicyRequest, QueueMember, QueueRole, UserInfo, READ_ACTION, WRITE_ACTION};
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    SqliteConnection,
+};
+use log::info;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::RwLock;
+use tokio::sync::Mutex;
+
+const MODEL_PATH: &str = "authz/abac_model.conf";
+/// Imported into the policy table when it is empty, e.g. on first start
+const SEED_POLICY_PATH: &str = "authz/abac_policy.csv";
+const POLICY_TYPE: &str = "p";
+/// Queue roles, stored as `g, <email>, <role>, <subapp>`
+const GROUPING_TYPE: &str = "g";
+/// Domain of requests that are not about a particular queue
+const NO_DOMAIN: &str = "";
+/// Admins must always be able to read and change this, or nobody could fix a bad policy
+const POLICIES_RESOURCE: &str = "/admin/policies";
+
+/// The policy is malformed or fails to evaluate
+#[derive(Debug)]
+pub struct InvalidPolicy(pub String);
+
+impl fmt::Display for InvalidPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid policy: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidPolicy {}
+
+/// The change would be rejected because it conflicts with the existing policies
+#[derive(Debug)]
+pub enum PolicyConflict {
+    Duplicate,
+    /// Admins would no longer be allowed to manage policies
+    Lockout,
+}
+
+impl fmt::Display for PolicyConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyConflict::Duplicate => f.write_str("The same policy already exists"),
+            PolicyConflict::Lockout => write!(
+                f,
+                "Admins would no longer be allowed to manage {}",
+                POLICIES_RESOURCE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PolicyConflict {}
+
+/// Hands casbin a snapshot of the `casbin_rule` table. Policies are changed in the database and
+/// the enforcer rebuilt, so casbin never writes through the adapter
+struct PolicyAdapter {
+    rows: Vec<PolicyRow>,
+}
+
+#[async_trait]
+impl Adapter for PolicyAdapter {
+    async fn load_policy(&self, m: &mut dyn Model) -> casbin::Result<()> {
+        for row in &self.rows {
+            let sec = &row.ptype[..1];
+            m.add_policy(sec, &row.ptype, policy_values(row));
+        }
+        Ok(())
+    }
+
+    async fn load_filtered_policy<'a>(
+        &mut self,
+        _m: &mut dyn Model,
+        _f: Filter<'a>,
+    ) -> casbin::Result<()> {
+        Err(read_only())
+    }
+
+    async fn save_policy(&mut self, _m: &mut dyn Model) -> casbin::Result<()> {
+        Err(read_only())
+    }
+
+    async fn clear_policy(&mut self) -> casbin::Result<()> {
+        Err(read_only())
+    }
+
+    fn is_filtered(&self) -> bool {
+        false
+    }
+
+    async fn add_policy(
+        &mut self,
+        _sec: &str,
+        _ptype: &str,
+        _rule: Vec<String>,
+    ) -> casbin::Result<bool> {
+        Err(read_only())
+    }
+
+    async fn add_policies(
+        &mut self,
+        _sec: &str,
+        _ptype: &str,
+        _rules: Vec<Vec<String>>,
+    ) -> casbin::Result<bool> {
+        Err(read_only())
+    }
+
+    async fn remove_policy(
+        &mut self,
+        _sec: &str,
+        _ptype: &str,
+        _rule: Vec<String>,
+    ) -> casbin::Result<bool> {
+        Err(read_only())
+    }
+
+    async fn remove_policies(
+        &mut self,
+        _sec: &str,
+        _ptype: &str,
+        _rules: Vec<Vec<String>>,
+    ) -> casbin::Result<bool> {
+        Err(read_only())
+    }
+
+    async fn remove_filtered_policy(
+        &mut self,
+        _sec: &str,
+        _ptype: &str,
+        _field_index: usize,
+        _field_values: Vec<String>,
+    ) -> casbin::Result<bool> {
+        Err(read_only())
+    }
+}
+
+fn read_only() -> casbin::Error {
+    AdapterError(Box::new(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Policies are changed through /admin/policies",
+    )))
+    .into()
+}
+
+/// The row's values without the unused trailing ones
+fn policy_values(row: &PolicyRow) -> Vec<String> {
+    let mut values = vec![
+        row.v0.clone(),
+        row.v1.clone(),
+        row.v2.clone(),
+        row.v3.clone(),
+        row.v4.clone(),
+        row.v5.clone(),
+    ];
+    while values.last().is_some_and(String::is_empty) {
+        values.pop();
+    }
+    values
+}
+
+fn to_policy(row: &PolicyRow) -> Policy {
+    Policy {
+        id: row.id,
+        rule: row.v0.clone(),
+        resource: row.v1.clone(),
+        action: row.v2.clone(),
+    }
+}
+
+fn to_row(id: i32, request: &PolicyRequest) -> PolicyRow {
+    PolicyRow {
+        id,
+        ptype: POLICY_TYPE.to_string(),
+        v0: request.rule.clone(),
+        v1: request.resource.clone(),
+        v2: request.action.clone(),
+        v3: String::new(),
+        v4: String::new(),
+        v5: String::new(),
+    }
+}
+
+fn to_grouping<'a>(email: &'a str, role: QueueRole, domain: &'a str) -> InsertPolicy<'a> {
+    InsertPolicy {
+        ptype: GROUPING_TYPE,
+        v0: email,
+        v1: role.as_str(),
+        v2: domain,
+    }
+}
+
+fn to_insert(request: &PolicyRequest) -> InsertPolicy<'_> {
+    InsertPolicy {
+        ptype: POLICY_TYPE,
+        v0: &request.rule,
+        v1: &request.resource,
+        v2: &request.action,
+    }
+}
+
+async fn build_enforcer(rows: Vec<PolicyRow>) -> casbin::Result<Enforcer> {
+    Enforcer::new(MODEL_PATH, PolicyAdapter { rows }).await
+}
+
+fn anonymous_user() -> UserInfo {
+    UserInfo {
+        email: ANONYMOUS.to_string(),
+        is_logged_in: false,
+        is_admin: false,
+        is_observer: false,
+        assigned_number: None,
+        roles: Vec::new(),
+    }
+}
+
+fn admin_user() -> UserInfo {
+    UserInfo {
+        email: "admin@example.com".to_string(),
+        is_logged_in: true,
+        is_admin: true,
+        is_observer: false,
+        assigned_number: None,
+        roles: Vec::new(),
+    }
+}
+
+/// Casbin enforcer over the policies in the database. Policy changes are validated against a
+/// fresh enforcer, then swapped in whole, so requests never see a half applied change
+pub struct PolicyEnforcer {
+    db_connection_pool: Pool<ConnectionManager<SqliteConnection>>,
+    enforcer: RwLock<Enforcer>,
+    /// Changes are applied one at a time, so none is lost between reading and swapping
+    update_lock: Mutex<()>,
+}
+
+impl PolicyEnforcer {
+    /// Load the policies, seeding the table from the CSV file if it has none
+    pub async fn new(
+        db_connection_pool: Pool<ConnectionManager<SqliteConnection>>,
+    ) -> AnyhowResult<Self> {
+        let rows = {
+            let db_connection = &mut db_connection_pool.get()?;
+            let mut rows = database::get_policies(db_connection)?;
+            if !rows.iter().any(|row| row.ptype == POLICY_TYPE) {
+                seed_policies(db_connection)?;
+                rows = database::get_policies(db_connection)?;
+            }
+            rows
+        };
+        let enforcer = build_enforcer(rows)
+            .await
+            .context("Failed to load policies")?;
+        Ok(PolicyEnforcer {
+            db_connection_pool,
+            enforcer: RwLock::new(enforcer),
+            update_lock: Mutex::new(()),
+        })
+    }
+
+    /// `domain` is the queue the request is about, or empty if it is not about one
+    pub fn enforce(
+        &self,
+        user_info: &UserInfo,
+        domain: &str,
+        resource: &str,
+        action: &str,
+    ) -> casbin::Result<bool> {
+        self.enforcer
+            .read()
+            .expect("Enforcer lock poisoned")
+            .enforce((user_info, domain, resource, action))
+    }
+
+    pub fn get_policies(&self) -> AnyhowResult<Vec<Policy>> {
+        let db_connection = &mut self.db_connection_pool.get()?;
+        let rows = database::get_policies(db_connection)?;
+        Ok(rows
+            .iter()
+            .filter(|row| row.ptype == POLICY_TYPE)
+            .map(to_policy)
+            .collect())
+    }
+
+    pub async fn add_policy(&self, request: PolicyRequest) -> AnyhowResult<Policy> {
+        let _guard = self.update_lock.lock().await;
+        let db_connection = &mut self.db_connection_pool.get()?;
+        if database::find_policy(db_connection, &to_insert(&request))?.is_some() {
+            return Err(PolicyConflict::Duplicate.into());
+        }
+        let mut rows = database::get_policies(db_connection)?;
+        rows.push(to_row(0, &request));
+        let enforcer = validate(rows).await?;
+        let id = database::insert_policy(db_connection, to_insert(&request))?;
+        self.swap(enforcer);
+        Ok(to_policy(&to_row(id, &request)))
+    }
+
+    /// None if there is no policy with the id
+    pub async fn update_policy(
+        &self,
+        id: i32,
+        request: PolicyRequest,
+    ) -> AnyhowResult<Option<Policy>> {
+        let _guard = self.update_lock.lock().await;
+        let db_connection = &mut self.db_connection_pool.get()?;
+        if database::get_policy(db_connection, id)?.is_none() {
+            return Ok(None);
+        }
+        if database::find_policy(db_connection, &to_insert(&request))?
+            .is_some_and(|existing| existing != id)
+        {
+            return Err(PolicyConflict::Duplicate.into());
+        }
+        let rows = database::get_policies(db_connection)?
+            .into_iter()
+            .map(|row| {
+                if row.id == id {
+                    to_row(id, &request)
+                } else {
+                    row
+                }
+            })
+            .collect();
+        let enforcer = validate(rows).await?;
+        database::update_policy(db_connection, id, to_insert(&request))?;
+        self.swap(enforcer);
+        Ok(Some(to_policy(&to_row(id, &request))))
+    }
+
+    /// The removed policy. None if there is no policy with the id
+    pub async fn remove_policy(&self, id: i32) -> AnyhowResult<Option<Policy>> {
+        let _guard = self.update_lock.lock().await;
+        let db_connection = &mut self.db_connection_pool.get()?;
+        let removed = match database::get_policy(db_connection, id)? {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let rows = database::get_policies(db_connection)?
+            .into_iter()
+            .filter(|row| row.id != id)
+            .collect();
+        let enforcer = validate(rows).await?;
+        database::delete_policy(db_connection, id)?;
+        self.swap(enforcer);
+        Ok(Some(to_policy(&removed)))
+    }
+
+    /// Owners first, then staff, each ordered by email
+    pub fn get_queue_members(&self, domain: &str) -> AnyhowResult<Vec<QueueMember>> {
+        let db_connection = &mut self.db_connection_pool.get()?;
+        let mut members = database::get_policies(db_connection)?
+            .into_iter()
+            .filter(|row| row.ptype == GROUPING_TYPE && row.v2 == domain)
+            .map(|row| {
+                Ok(QueueMember {
+                    role: QueueRole::from_str(&row.v1).map_err(|e| anyhow!(e))?,
+                    email: row.v0,
+                })
+            })
+            .collect::<AnyhowResult<Vec<QueueMember>>>()?;
+        members.sort_by(|a, b| {
+            (a.role != QueueRole::Owner, &a.email).cmp(&(b.role != QueueRole::Owner, &b.email))
+        });
+        Ok(members)
+    }
+
+    /// Give the user a role within the queue. Returns false if they already had it
+    pub async fn add_queue_member(
+        &self,
+        email: &str,
+        role: QueueRole,
+        domain: &str,
+    ) -> AnyhowResult<bool> {
+        let _guard = self.update_lock.lock().await;
+        let db_connection = &mut self.db_connection_pool.get()?;
+        let email = normalise_email(email);
+        let grouping = to_grouping(&email, role, domain);
+        if database::find_policy(db_connection, &grouping)?.is_some() {
+            return Ok(false);
+        }
+        database::insert_policy(db_connection, grouping)?;
+        self.reload(db_connection).await?;
+        Ok(true)
+    }
+
+    /// Returns false if the user did not have the role
+    pub async fn remove_queue_member(
+        &self,
+        email: &str,
+        role: QueueRole,
+        domain: &str,
+    ) -> AnyhowResult<bool> {
+        let _guard = self.update_lock.lock().await;
+        let db_connection = &mut self.db_connection_pool.get()?;
+        let email = normalise_email(email);
+        let id = match database::find_policy(db_connection, &to_grouping(&email, role, domain))? {
+            Some(id) => id,
+            None => return Ok(false),
+        };
+        database::delete_policy(db_connection, id)?;
+        self.reload(db_connection).await?;
+        Ok(true)
+    }
+
+    /// Queue roles cannot break rules, so they are applied without validation
+    async fn reload(&self, db_connection: &mut SqliteConnection) -> AnyhowResult<()> {
+        let rows = database::get_policies(db_connection)?;
+        let enforcer = build_enforcer(rows)
+            .await
+            .context("Failed to reload policies")?;
+        self.swap(enforcer);
+        Ok(())
+    }
+
+    fn swap(&self, enforcer: Enforcer) {
+        *self.enforcer.write().expect("Enforcer lock poisoned") = enforcer;
+    }
+}
+
+/// Build an enforcer for the policies and make sure every rule evaluates and admins keep access
+async fn validate(rows: Vec<PolicyRow>) -> AnyhowResult<Enforcer> {
+    let enforcer = build_enforcer(rows)
+        .await
+        .map_err(|e| InvalidPolicy(e.to_string()))?;
+    // Every rule is evaluated on each request, so one broken rule fails them all
+    enforcer
+        .enforce((&anonymous_user(), NO_DOMAIN, POLICIES_RESOURCE, READ_ACTION))
+        .map_err(|e| InvalidPolicy(e.to_string()))?;
+    for action in [READ_ACTION, WRITE_ACTION] {
+        let admin_allowed = enforcer
+            .enforce((&admin_user(), NO_DOMAIN, POLICIES_RESOURCE, action))
+            .map_err(|e| InvalidPolicy(e.to_string()))?;
+        if !admin_allowed {
+            return Err(PolicyConflict::Lockout.into());
+        }
+    }
+    Ok(enforcer)
+}
+
+/// Import `p, <rule>, <resource>, <action>` lines. Rules may contain commas, resources and
+/// actions may not
+fn seed_policies(db_connection: &mut SqliteConnection) -> AnyhowResult<()> {
+    let seed = std::fs::read_to_string(SEED_POLICY_PATH)
+        .with_context(|| format!("Failed to read {}", SEED_POLICY_PATH))?;
+    for line in seed.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let values = line
+            .strip_prefix("p,")
+            .ok_or_else(|| anyhow!("Expected a p policy, got {}", line))?;
+        let mut values = values.rsplitn(3, ',').map(str::trim);
+        let (action, resource, rule) = match (values.next(), values.next(), values.next()) {
+            (Some(action), Some(resource), Some(rule)) => (action, resource, rule),
+            _ => {
+                return Err(anyhow!(
+                    "Expected p, <rule>, <resource>, <action>, got {}",
+                    line
+                ))
+            }
+        };
+        database::insert_policy(
+            db_connection,
+            InsertPolicy {
+                ptype: POLICY_TYPE,
+                v0: rule,
+                v1: resource,
+                v2: action,
+            },
+        )?;
+    }
+    info!("Seeded policies from {}", SEED_POLICY_PATH);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    const ADMIN_RULE: &str = "(r.subject.is_admin == true && r.subject.is_logged_in == true)";
+
+    async fn enforcer() -> PolicyEnforcer {
+        test_support::policy_enforcer(test_support::connection_pool()).await
+    }
+
+    /// The seed policy that lets admins manage everything under `/admin`
+    fn find_admin_policy(enforcer: &PolicyEnforcer) -> Policy {
+        enforcer
+            .get_policies()
+            .unwrap()
+            .into_iter()
+            .find(|policy| policy.rule == ADMIN_RULE && policy.resource == "/admin/*")
+            .expect("Seed policies have an admin rule")
+    }
+
+    fn request(rule: &str, resource: &str, action: &str) -> PolicyRequest {
+        PolicyRequest {
+            rule: rule.to_string(),
+            resource: resource.to_string(),
+            action: action.to_string(),
+        }
+    }
+
+    fn is_lockout(result: AnyhowResult<impl fmt::Debug>) -> bool {
+        matches!(
+            result.unwrap_err().downcast_ref::<PolicyConflict>(),
+            Some(PolicyConflict::Lockout)
+        )
+    }
+
+    fn admin_can_manage_policies(enforcer: &PolicyEnforcer) -> bool {
+        [READ_ACTION, WRITE_ACTION].iter().all(|action| {
+            enforcer
+                .enforce(&admin_user(), NO_DOMAIN, POLICIES_RESOURCE, action)
+                .unwrap()
+        })
+    }
+
+    #[actix_web::test]
+    async fn removing_the_admin_rule_is_rejected() {
+        let enforcer = enforcer().await;
+        let admin_policy = find_admin_policy(&enforcer);
+        assert!(is_lockout(enforcer.remove_policy(admin_policy.id).await));
+        assert_eq!(find_admin_policy(&enforcer), admin_policy);
+        assert!(admin_can_manage_policies(&enforcer));
+    }
+
+    #[actix_web::test]
+    async fn narrowing_the_admin_rule_is_rejected() {
+        let enforcer = enforcer().await;
+        let admin_policy = find_admin_policy(&enforcer);
+        for change in [
+            request(ADMIN_RULE, "/admin/*", READ_ACTION),
+            request(ADMIN_RULE, "/admin/users", "*"),
+            request("r.subject.is_observer == true", "/admin/*", "*"),
+        ] {
+            let result = enforcer.update_policy(admin_policy.id, change).await;
+            assert!(is_lockout(result));
+        }
+        assert_eq!(find_admin_policy(&enforcer), admin_policy);
+        assert!(admin_can_manage_policies(&enforcer));
+    }
+
+    #[actix_web::test]
+    async fn harmless_changes_are_applied() {
+        let enforcer = enforcer().await;
+        let observer = UserInfo {
+            is_admin: false,
+            is_observer: true,
+            ..admin_user()
+        };
+        let allowed = |enforcer: &PolicyEnforcer| {
+            enforcer
+                .enforce(&observer, NO_DOMAIN, "/admin/users", WRITE_ACTION)
+                .unwrap()
+        };
+        assert!(!allowed(&enforcer));
+
+        let added = enforcer
+            .add_policy(request(
+                "r.subject.is_observer == true",
+                "/admin/users",
+                WRITE_ACTION,
+            ))
+            .await
+            .unwrap();
+        assert!(enforcer.get_policies().unwrap().contains(&added));
+        assert!(allowed(&enforcer));
+
+        let removed = enforcer.remove_policy(added.id).await.unwrap();
+        assert_eq!(removed, Some(added));
+        assert!(!allowed(&enforcer));
+    }
+
+    #[actix_web::test]
+    async fn duplicates_and_broken_rules_are_rejected() {
+        let enforcer = enforcer().await;
+        let admin_policy = find_admin_policy(&enforcer);
+        let duplicate = request(ADMIN_RULE, "/admin/*", "*");
+        let result = enforcer.add_policy(duplicate).await.unwrap_err();
+        assert!(matches!(
+            result.downcast_ref::<PolicyConflict>(),
+            Some(PolicyConflict::Duplicate)
+        ));
+        let broken = request("r.subject.no_such_field ==", "/api/*", READ_ACTION);
+        let result = enforcer.add_policy(broken).await.unwrap_err();
+        assert!(result.downcast_ref::<InvalidPolicy>().is_some());
+        assert_eq!(find_admin_policy(&enforcer), admin_policy);
+    }
+}
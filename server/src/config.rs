@@ -0,0 +1,378 @@
+use anyhow::{anyhow, Context, Result as AnyhowResult};
+use common::LoginProvider;
+use openidconnect::{url::Url, ClientId, ClientSecret, IssuerUrl, RedirectUrl, Scope};
+use std::collections::HashSet;
+use std::env;
+use std::time::Duration;
+
+/// Comma separated provider names. Each provider is configured with `OIDC_{NAME}_*` variables
+const OIDC_PROVIDERS_KEY: &str = "OIDC_PROVIDERS";
+const OIDC_PREFIX: &str = "OIDC";
+const ISSUER_URL_KEY: &str = "ISSUER_URL";
+const CLIENT_ID_KEY: &str = "CLIENT_ID";
+const CLIENT_SECRET_KEY: &str = "CLIENT_SECRET";
+const SCOPES_KEY: &str = "SCOPES";
+const DISPLAY_NAME_KEY: &str = "DISPLAY_NAME";
+const ROLE_CLAIMS_KEY: &str = "ROLE_CLAIMS";
+const PUBLIC_BASE_URL_KEY: &str = "PUBLIC_BASE_URL";
+const OIDC_DISCOVERY_TTL_SECONDS_KEY: &str = "OIDC_DISCOVERY_TTL_SECONDS";
+// Still accepted so existing Google deployments keep working
+const GOOGLE_CLIENT_ID_KEY: &str = "GOOGLE_CLIENT_ID";
+const GOOGLE_CLIENT_SECRET_KEY: &str = "GOOGLE_CLIENT_SECRET";
+
+/// Comma separated names of downstream APIs. Each is configured with `REMOTE_{NAME}_*` variables
+const REMOTE_ROUTES_KEY: &str = "REMOTE_ROUTES";
+const REMOTE_PREFIX: &str = "REMOTE";
+const URL_KEY: &str = "URL";
+const REQUIRE_CSRF_KEY: &str = "REQUIRE_CSRF";
+
+/// Name of the provider configured with unprefixed `OIDC_*` variables when `OIDC_PROVIDERS` is unset
+const DEFAULT_PROVIDER_NAME: &str = "default";
+const DEFAULT_ISSUER_URL: &str = "https://accounts.google.com";
+const DEFAULT_SCOPES: &str = "email profile";
+const DEFAULT_PUBLIC_BASE_URL: &str = "http://localhost:8080";
+const DEFAULT_DISCOVERY_TTL_SECONDS: &str = "3600";
+const TOKEN_EXCHANGE_PATH: &str = "/public/token_exchange";
+const LOGGED_OUT_PATH: &str = "/public/logged_out";
+/// ID token claims that identify the user or the token rather than what the user may do
+const RESERVED_CLAIMS: [&str; 10] = [
+    "iss",
+    "sub",
+    "aud",
+    "exp",
+    "iat",
+    "nonce",
+    "email",
+    "email_verified",
+    "azp",
+    "auth_time",
+];
+
+/// All the OpenID Connect issuers users can log in with
+#[derive(Clone, Debug)]
+pub struct OidcConfig {
+    /// In the order they were configured. Never empty
+    pub providers: Vec<OidcProviderConfig>,
+    /// How long discovery metadata and signing keys are used before they are fetched again
+    pub discovery_ttl: Duration,
+}
+
+/// Everything needed to log users in against one OpenID Connect issuer
+#[derive(Clone, Debug)]
+pub struct OidcProviderConfig {
+    /// Used in login URLs
+    pub name: String,
+    /// Shown on the login button
+    pub display_name: String,
+    pub issuer_url: IssuerUrl,
+    pub client_id: ClientId,
+    pub client_secret: ClientSecret,
+    /// Requested on top of `openid`, which is always asked for
+    pub scopes: Vec<Scope>,
+    /// Where the issuer sends users back to, derived from the public base URL
+    pub redirect_url: RedirectUrl,
+    /// Where the issuer sends users back to after logging out, also derived from the public base
+    /// URL. Has to be registered with the issuer along with `redirect_url`
+    pub post_logout_redirect_url: Url,
+    /// ID token claims, e.g. `groups`, whose values become the user's roles
+    pub role_claims: Vec<String>,
+}
+
+impl OidcConfig {
+    /// Read the OIDC config from environment variables, failing on anything missing or malformed
+    pub fn from_env() -> AnyhowResult<Self> {
+        let public_base_url = env_or_default(PUBLIC_BASE_URL_KEY, DEFAULT_PUBLIC_BASE_URL);
+        let providers = match env::var(OIDC_PROVIDERS_KEY) {
+            Ok(names) => names
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(|name| {
+                    let prefix =
+                        format!("{}_{}", OIDC_PREFIX, name.to_uppercase().replace('-', "_"));
+                    OidcProviderConfig::from_env(name, &prefix, &public_base_url)
+                        .with_context(|| format!("Invalid config for provider {}", name))
+                })
+                .collect::<AnyhowResult<Vec<OidcProviderConfig>>>()?,
+            Err(_) => vec![OidcProviderConfig::from_env(
+                DEFAULT_PROVIDER_NAME,
+                OIDC_PREFIX,
+                &public_base_url,
+            )?],
+        };
+        let discovery_ttl = env_or_default(
+            OIDC_DISCOVERY_TTL_SECONDS_KEY,
+            DEFAULT_DISCOVERY_TTL_SECONDS,
+        );
+        let discovery_ttl = discovery_ttl
+            .parse::<u64>()
+            .ok()
+            .filter(|seconds| *seconds > 0)
+            .map(Duration::from_secs)
+            .ok_or_else(|| {
+                anyhow!(
+                    "{} must be a positive number of seconds, got {}",
+                    OIDC_DISCOVERY_TTL_SECONDS_KEY,
+                    discovery_ttl
+                )
+            })?;
+        Self::new(providers, discovery_ttl)
+    }
+
+    pub fn new(providers: Vec<OidcProviderConfig>, discovery_ttl: Duration) -> AnyhowResult<Self> {
+        if providers.is_empty() {
+            return Err(anyhow!(
+                "{} must name at least one provider",
+                OIDC_PROVIDERS_KEY
+            ));
+        }
+        let mut names = HashSet::new();
+        for provider in &providers {
+            if !names.insert(provider.name.as_str()) {
+                return Err(anyhow!("Provider {} is configured twice", provider.name));
+            }
+        }
+        Ok(OidcConfig {
+            providers,
+            discovery_ttl,
+        })
+    }
+
+    pub fn provider(&self, name: &str) -> Option<&OidcProviderConfig> {
+        self.providers.iter().find(|provider| provider.name == name)
+    }
+
+    /// The provider used by the plain login URL
+    pub fn default_provider(&self) -> &OidcProviderConfig {
+        &self.providers[0]
+    }
+
+    /// What the login page needs to offer each provider
+    pub fn login_providers(&self) -> Vec<LoginProvider> {
+        self.providers
+            .iter()
+            .map(|provider| LoginProvider {
+                name: provider.name.clone(),
+                display_name: provider.display_name.clone(),
+            })
+            .collect()
+    }
+}
+
+impl OidcProviderConfig {
+    /// Read one provider from `{prefix}_*` environment variables
+    fn from_env(name: &str, prefix: &str, public_base_url: &str) -> AnyhowResult<Self> {
+        let key = |suffix: &str| format!("{}_{}", prefix, suffix);
+        let issuer_url = env_or_default(&key(ISSUER_URL_KEY), DEFAULT_ISSUER_URL);
+        let (client_id, client_secret) = if name == DEFAULT_PROVIDER_NAME {
+            (
+                env_with_fallback(&key(CLIENT_ID_KEY), GOOGLE_CLIENT_ID_KEY)?,
+                env_with_fallback(&key(CLIENT_SECRET_KEY), GOOGLE_CLIENT_SECRET_KEY)?,
+            )
+        } else {
+            (
+                env_required(&key(CLIENT_ID_KEY))?,
+                env_required(&key(CLIENT_SECRET_KEY))?,
+            )
+        };
+        let scopes = env_or_default(&key(SCOPES_KEY), DEFAULT_SCOPES);
+        let display_name = env::var(key(DISPLAY_NAME_KEY)).unwrap_or_else(|_| {
+            if name == DEFAULT_PROVIDER_NAME {
+                "Login".to_string()
+            } else {
+                format!("Login with {}", name)
+            }
+        });
+        let role_claims = env::var(key(ROLE_CLAIMS_KEY)).unwrap_or_default();
+        Self::new(
+            name,
+            display_name,
+            &issuer_url,
+            client_id,
+            client_secret,
+            &scopes,
+            public_base_url,
+        )?
+        .with_role_claims(&role_claims)
+    }
+
+    /// Validate and assemble the config. Scopes are separated by whitespace or commas
+    pub fn new(
+        name: &str,
+        display_name: String,
+        issuer_url: &str,
+        client_id: String,
+        client_secret: String,
+        scopes: &str,
+        public_base_url: &str,
+    ) -> AnyhowResult<Self> {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        {
+            return Err(anyhow!(
+                "Provider name {} may only contain lowercase letters, digits and dashes",
+                name
+            ));
+        }
+        let issuer_url = IssuerUrl::new(issuer_url.to_string())
+            .with_context(|| format!("{} is not a valid URL", issuer_url))?;
+        if issuer_url.url().scheme() != "https" && !is_local(issuer_url.url()) {
+            return Err(anyhow!(
+                "Issuer {} must use https",
+                issuer_url.url().as_str()
+            ));
+        }
+        if client_id.trim().is_empty() {
+            return Err(anyhow!("Client id must not be empty"));
+        }
+        if client_secret.is_empty() {
+            return Err(anyhow!("Client secret must not be empty"));
+        }
+        let scopes = scopes
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|scope| !scope.is_empty() && *scope != "openid")
+            .map(|scope| Scope::new(scope.to_string()))
+            .collect::<Vec<Scope>>();
+        if !scopes.iter().any(|scope| scope.as_str() == "email") {
+            return Err(anyhow!(
+                "Scopes must include email, users are identified by their email"
+            ));
+        }
+        let redirect_url = RedirectUrl::new(format!(
+            "{}{}",
+            public_base_url.trim_end_matches('/'),
+            TOKEN_EXCHANGE_PATH
+        ))
+        .with_context(|| format!("{} is not a valid URL", public_base_url))?;
+        if !matches!(redirect_url.url().scheme(), "http" | "https") {
+            return Err(anyhow!(
+                "Public base URL {} must be http or https",
+                public_base_url
+            ));
+        }
+        let post_logout_redirect_url = Url::parse(&format!(
+            "{}{}",
+            public_base_url.trim_end_matches('/'),
+            LOGGED_OUT_PATH
+        ))
+        .with_context(|| format!("{} is not a valid URL", public_base_url))?;
+
+        Ok(OidcProviderConfig {
+            name: name.to_string(),
+            display_name,
+            issuer_url,
+            client_id: ClientId::new(client_id),
+            client_secret: ClientSecret::new(client_secret),
+            scopes,
+            redirect_url,
+            post_logout_redirect_url,
+            role_claims: Vec::new(),
+        })
+    }
+
+    /// Take roles from the given claims, separated by whitespace or commas
+    pub fn with_role_claims(mut self, role_claims: &str) -> AnyhowResult<Self> {
+        let role_claims = role_claims
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|claim| !claim.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<String>>();
+        // Standard claims have their own meaning and are never roles
+        for claim in &role_claims {
+            if RESERVED_CLAIMS.contains(&claim.as_str()) {
+                return Err(anyhow!("Claim {} cannot be used for roles", claim));
+            }
+        }
+        self.role_claims = role_claims;
+        Ok(self)
+    }
+}
+
+/// A downstream API exposed to the browser under `/remote/{name}/*`
+#[derive(Clone, Debug)]
+pub struct RemoteRouteConfig {
+    pub name: String,
+    /// Requests are forwarded to this URL with the rest of the path appended
+    pub url: Url,
+    /// Reject requests without the anti-forgery header
+    pub require_csrf: bool,
+}
+
+impl RemoteRouteConfig {
+    /// Read every route listed in `REMOTE_ROUTES`. No routes if it is unset
+    pub fn all_from_env() -> AnyhowResult<Vec<Self>> {
+        let names = env::var(REMOTE_ROUTES_KEY).unwrap_or_default();
+        let mut routes = Vec::<RemoteRouteConfig>::new();
+        for name in names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let prefix = format!(
+                "{}_{}",
+                REMOTE_PREFIX,
+                name.to_uppercase().replace('-', "_")
+            );
+            let key = |suffix: &str| format!("{}_{}", prefix, suffix);
+            let route = Self::new(
+                name,
+                &env_required(&key(URL_KEY))?,
+                &env_or_default(&key(REQUIRE_CSRF_KEY), "true"),
+            )
+            .with_context(|| format!("Invalid config for remote route {}", name))?;
+            if routes.iter().any(|existing| existing.name == route.name) {
+                return Err(anyhow!("Remote route {} is configured twice", name));
+            }
+            routes.push(route);
+        }
+        Ok(routes)
+    }
+
+    pub fn new(name: &str, url: &str, require_csrf: &str) -> AnyhowResult<Self> {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        {
+            return Err(anyhow!(
+                "Remote route name {} may only contain lowercase letters, digits and dashes",
+                name
+            ));
+        }
+        let url = Url::parse(url).with_context(|| format!("{} is not a valid URL", url))?;
+        if !matches!(url.scheme(), "http" | "https") || url.cannot_be_a_base() {
+            return Err(anyhow!(
+                "Remote URL {} must be an http or https base URL",
+                url
+            ));
+        }
+        let require_csrf = require_csrf
+            .parse::<bool>()
+            .with_context(|| format!("Expected true or false, got {}", require_csrf))?;
+        Ok(RemoteRouteConfig {
+            name: name.to_string(),
+            url,
+            require_csrf,
+        })
+    }
+}
+
+fn is_local(url: &Url) -> bool {
+    matches!(url.host_str(), Some("localhost") | Some("127.0.0.1"))
+}
+
+fn env_or_default(key: &str, default: &str) -> String {
+    env::var(key).unwrap_or_else(|_| default.to_string())
+}
+
+fn env_required(key: &str) -> AnyhowResult<String> {
+    env::var(key).with_context(|| format!("Missing the {} environment variable", key))
+}
+
+fn env_with_fallback(key: &str, fallback_key: &str) -> AnyhowResult<String> {
+    env::var(key)
+        .or_else(|_| env::var(fallback_key))
+        .with_context(|| format!("Missing the {} environment variable", key))
+}
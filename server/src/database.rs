@@ -1,12 +1,21 @@
-use crate::schema::queue;
+use crate::roles::LastAdmin;
+use crate::schema::{
+    casbin_rule, pending_logins, queues, sessions, tickets, user_roles, user_tokens, users,
+};
 use crate::ANONYMOUS;
 use anyhow::{anyhow, Context, Result as AnyhowResult};
 use chrono::prelude::*;
-use common::UserInfo;
+use common::{
+    CreateQueueRequest, QueueSummary, Role, ServerSentData, TicketStatus, UserInfo, UserRoles,
+};
 use diesel::prelude::*;
 use diesel::r2d2::ConnectionManager;
 use diesel::r2d2::Pool;
 use std::env;
+use std::str::FromStr;
+
+/// Number of most recently served numbers used to average service times
+const SERVICE_TIME_WINDOW: i64 = 20;
 
 pub fn establish_connection_pool() -> Pool<ConnectionManager<SqliteConnection>> {
     let database_url = env::var(crate::DATABASE_URL_KEY).unwrap_or_else(|_| {
@@ -23,27 +32,100 @@ pub fn establish_connection_pool() -> Pool<ConnectionManager<SqliteConnection>>
 }
 
 #[derive(Queryable, Insertable)]
-#[diesel(table_name = queue)]
+#[diesel(table_name = tickets)]
 pub struct InsertQueueItem {
+    pub queue_id: i32,
     pub user: String,
-    pub is_selected: bool,
-    pub is_processed: bool,
-    pub is_abandoned: bool,
+    pub status: String,
     pub updated_at: NaiveDateTime,
 }
 
-/// Insert new entry into queue. Fails if user already exists in queue
-pub fn insert_into_queue(con: &mut SqliteConnection, user: String) -> AnyhowResult<()> {
+#[derive(Queryable)]
+pub struct QueueRecord {
+    pub id: i32,
+    pub slug: String,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+    pub description: String,
+    pub owner: Option<String>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = queues)]
+pub struct InsertQueue {
+    pub slug: String,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+    pub description: String,
+    pub owner: Option<String>,
+}
+
+/// Look up the queue backing a subapp
+pub fn get_queue(con: &mut SqliteConnection, subapp: &str) -> AnyhowResult<Option<QueueRecord>> {
+    queues::table
+        .filter(queues::slug.eq(subapp))
+        .first::<QueueRecord>(con)
+        .optional()
+        .context("Failed to query queues table")
+}
+
+/// List every queue along with how many numbers are waiting and the currently selected number
+pub fn get_queue_summaries(con: &mut SqliteConnection) -> AnyhowResult<Vec<QueueSummary>> {
+    let queue_records = queues::table
+        .order(queues::name.asc())
+        .load::<QueueRecord>(con)
+        .context("Failed to query queues table")?;
+    queue_records
+        .into_iter()
+        .map(|queue| {
+            let waiting_count = tickets::table
+                .filter(tickets::queue_id.eq(queue.id))
+                .filter(tickets::status.eq(TicketStatus::Waiting.as_str()))
+                .count()
+                .get_result::<i64>(con)
+                .context("Failed to count waiting numbers")?;
+            let current_number = get_selected_queue(con, queue.id)?;
+            Ok(QueueSummary {
+                name: queue.name,
+                slug: queue.slug,
+                waiting_count,
+                current_number,
+            })
+        })
+        .collect()
+}
+
+/// Create a new queue owned by the given user. Fails if the slug is already taken
+pub fn create_queue(
+    con: &mut SqliteConnection,
+    request: CreateQueueRequest,
+    owner: &str,
+) -> AnyhowResult<QueueRecord> {
+    let new_queue = InsertQueue {
+        slug: request.slug.clone(),
+        name: request.name.trim().to_string(),
+        created_at: Utc::now().naive_utc(),
+        description: request.description.trim().to_string(),
+        owner: Some(owner.to_string()),
+    };
+    diesel::insert_into(queues::table)
+        .values(new_queue)
+        .execute(con)
+        .context("Failed to insert queue into database")?;
+    get_queue(con, &request.slug)?.ok_or_else(|| anyhow!("Queue {} was not created", request.slug))
+}
+
+/// Insert new entry into queue. Fails if user already holds an active ticket in the queue
+fn insert_into_queue(con: &mut SqliteConnection, queue_id: i32, user: String) -> AnyhowResult<()> {
     let current_time = Utc::now().naive_utc();
     let new_queue_item = InsertQueueItem {
+        queue_id,
         user,
-        is_selected: false,
-        is_processed: false,
-        is_abandoned: false,
+        status: TicketStatus::INITIAL.as_str().to_string(),
         updated_at: current_time,
     };
 
-    diesel::insert_into(queue::table)
+    diesel::insert_into(tickets::table)
         .values(new_queue_item)
         .execute(con)
         .context("Failed to insert row into database")?;
@@ -53,33 +135,86 @@ pub fn insert_into_queue(con: &mut SqliteConnection, user: String) -> AnyhowResu
 #[derive(Queryable)]
 pub struct QueueRow {
     pub id: i32,
+    pub queue_id: i32,
     pub user: String,
-    pub is_selected: bool,
-    pub is_processed: bool,
-    pub is_abandoned: bool,
+    pub status: String,
     pub updated_at: NaiveDateTime,
+    pub called_at: Option<NaiveDateTime>,
+    pub served_at: Option<NaiveDateTime>,
 }
 
-// Given user's email, try to obtain current assigned queue
-pub fn get_user_assigned_queue(
+impl QueueRow {
+    pub fn status(&self) -> AnyhowResult<TicketStatus> {
+        TicketStatus::from_str(&self.status).map_err(|e| anyhow!(e))
+    }
+}
+
+/// Move a ticket to a new status. Illegal moves are rejected with an `IllegalTransition` error
+/// and leave the row untouched
+pub fn transition_ticket(
+    con: &mut SqliteConnection,
+    ticket: &QueueRow,
+    next_status: TicketStatus,
+) -> AnyhowResult<()> {
+    let current_status = ticket.status()?;
+    let next_status = current_status.transition(next_status)?;
+    let current_time = Utc::now().naive_utc();
+    // Record when the ticket was called and served, for estimating wait times
+    let called_at = match next_status {
+        TicketStatus::Called => Some(current_time),
+        _ => ticket.called_at,
+    };
+    let served_at = match next_status {
+        TicketStatus::Served => Some(current_time),
+        _ => ticket.served_at,
+    };
+    // Only update if nobody else moved the ticket in the meantime
+    let updated = diesel::update(tickets::table)
+        .filter(tickets::id.eq(ticket.id))
+        .filter(tickets::status.eq(current_status.as_str()))
+        .set((
+            tickets::status.eq(next_status.as_str()),
+            tickets::updated_at.eq(current_time),
+            tickets::called_at.eq(called_at),
+            tickets::served_at.eq(served_at),
+        ))
+        .execute(con)
+        .with_context(|| format!("Failed to set ticket {} to {}", ticket.id, next_status))?;
+    match updated {
+        1 => Ok(()),
+        _ => Err(anyhow!(
+            "Ticket {} was no longer {}",
+            ticket.id,
+            current_status
+        )),
+    }
+}
+
+fn status_strs(statuses: &[TicketStatus]) -> Vec<&'static str> {
+    statuses.iter().map(|status| status.as_str()).collect()
+}
+
+/// Given user's email, try to obtain the ticket still holding their place in the queue
+fn get_user_assigned_ticket(
     con: &mut SqliteConnection,
+    queue_id: i32,
     provided_user: &str,
-) -> AnyhowResult<Option<i32>> {
+) -> AnyhowResult<Option<QueueRow>> {
     // If user is anonymous, skip database check
     if provided_user == ANONYMOUS {
         return Ok(None);
     }
 
-    let results = queue::table
-        .filter(queue::user.eq(provided_user))
-        .filter(queue::is_processed.eq(false))
-        .filter(queue::is_abandoned.eq(false))
+    let mut results = tickets::table
+        .filter(tickets::queue_id.eq(queue_id))
+        .filter(tickets::user.eq(provided_user))
+        .filter(tickets::status.eq_any(status_strs(&TicketStatus::ACTIVE)))
         .load::<QueueRow>(con)
         .context("Failed to query queue table")?;
 
     match results.len() {
         0 => Ok(None),
-        1 => Ok(Some(results[0].id)),
+        1 => Ok(results.pop()),
         _ => Err(anyhow!(
             "Expected 0 or 1 row. Found {} rows instead.",
             results.len()
@@ -87,50 +222,172 @@ pub fn get_user_assigned_queue(
     }
 }
 
-/// Helper function
-pub fn get_or_insert(con: &mut SqliteConnection, user_struct: UserInfo) -> AnyhowResult<UserInfo> {
-    let x = get_user_assigned_queue(con, &user_struct.email)?;
+// Given user's email, try to obtain current assigned queue
+pub fn get_user_assigned_queue(
+    con: &mut SqliteConnection,
+    queue_id: i32,
+    provided_user: &str,
+) -> AnyhowResult<Option<i32>> {
+    Ok(get_user_assigned_ticket(con, queue_id, provided_user)?.map(|ticket| ticket.id))
+}
 
-    match x {
-        Some(number) => {
-            // Already has a number, we return
-            let mut result = user_struct.clone();
-            result.assigned_number = Some(number);
-            Ok(result)
-        }
-        None => {
-            // No number. So we assign a number
-            insert_into_queue(con, user_struct.email.clone())?;
-            // recurse function
-            get_or_insert(con, user_struct)
-        }
+/// Count the waiting numbers ahead of the user's, ordered by creation.
+/// Returns None if the user does not have a waiting number
+pub fn get_people_ahead(
+    con: &mut SqliteConnection,
+    queue_id: i32,
+    provided_user: &str,
+) -> AnyhowResult<Option<i64>> {
+    let ticket = match get_user_assigned_ticket(con, queue_id, provided_user)? {
+        Some(ticket) if ticket.status()? == TicketStatus::Waiting => ticket,
+        _ => return Ok(None),
+    };
+    let people_ahead = tickets::table
+        .filter(tickets::queue_id.eq(queue_id))
+        .filter(tickets::status.eq(TicketStatus::Waiting.as_str()))
+        .filter(tickets::id.lt(ticket.id))
+        .count()
+        .get_result::<i64>(con)
+        .context("Failed to count waiting numbers")?;
+    Ok(Some(people_ahead))
+}
+
+/// Rolling average of the time between calling and serving a number,
+/// over the most recently served numbers of the queue
+pub fn get_average_service_seconds(
+    con: &mut SqliteConnection,
+    queue_id: i32,
+) -> AnyhowResult<Option<i64>> {
+    let served_tickets = tickets::table
+        .filter(tickets::queue_id.eq(queue_id))
+        .filter(tickets::status.eq(TicketStatus::Served.as_str()))
+        .filter(tickets::called_at.is_not_null())
+        .filter(tickets::served_at.is_not_null())
+        .order(tickets::served_at.desc())
+        .limit(SERVICE_TIME_WINDOW)
+        .load::<QueueRow>(con)
+        .context("Failed to query served numbers")?;
+    let durations: Vec<i64> = served_tickets
+        .iter()
+        .filter_map(|ticket| match (ticket.called_at, ticket.served_at) {
+            (Some(called_at), Some(served_at)) => {
+                Some((served_at - called_at).num_seconds().max(0))
+            }
+            _ => None,
+        })
+        .collect();
+    if durations.is_empty() {
+        return Ok(None);
     }
+    Ok(Some(durations.iter().sum::<i64>() / durations.len() as i64))
+}
+
+/// Estimate how long until a waiting user is called: one average service time for each number
+/// ahead of them, plus whatever is left of the number currently at the counter.
+/// Returns None if the user is not waiting or the queue has no service history yet
+pub fn get_estimated_wait_seconds(
+    con: &mut SqliteConnection,
+    queue_id: i32,
+    people_ahead: Option<i64>,
+) -> AnyhowResult<Option<i64>> {
+    let people_ahead = match people_ahead {
+        Some(people_ahead) => people_ahead,
+        None => return Ok(None),
+    };
+    let average_service_seconds = match get_average_service_seconds(con, queue_id)? {
+        Some(average_service_seconds) => average_service_seconds,
+        None => return Ok(None),
+    };
+    let remaining_current_seconds = match get_selected_ticket(con, queue_id)? {
+        Some(QueueRow {
+            called_at: Some(called_at),
+            ..
+        }) => {
+            let elapsed_seconds = (Utc::now().naive_utc() - called_at).num_seconds();
+            (average_service_seconds - elapsed_seconds).max(0)
+        }
+        _ => 0,
+    };
+    Ok(Some(
+        people_ahead * average_service_seconds + remaining_current_seconds,
+    ))
+}
+
+/// Everything a user sees about a queue: the selected number and their own place in it
+pub fn get_server_sent_data(
+    con: &mut SqliteConnection,
+    queue_id: i32,
+    provided_user: &str,
+) -> AnyhowResult<ServerSentData> {
+    let selected_number = get_selected_queue(con, queue_id)?;
+    let assigned_number = get_user_assigned_queue(con, queue_id, provided_user)?;
+    let (abandoned_numbers, done_numbers) =
+        get_abandoned_and_processed(con, queue_id, provided_user)?;
+    let people_ahead = get_people_ahead(con, queue_id, provided_user)?;
+    let estimated_wait_seconds = get_estimated_wait_seconds(con, queue_id, people_ahead)?;
+    Ok(ServerSentData {
+        selected_number,
+        assigned_number,
+        abandoned_numbers,
+        done_numbers,
+        position: people_ahead.map(|x| x + 1),
+        people_ahead,
+        estimated_wait_seconds,
+    })
+}
+
+/// Helper function
+pub fn get_or_insert(
+    con: &mut SqliteConnection,
+    queue_id: i32,
+    user_struct: UserInfo,
+) -> AnyhowResult<UserInfo> {
+    // Check and insert together, so concurrent requests from the same user get the same number
+    con.immediate_transaction(|con| {
+        let number = match get_user_assigned_queue(con, queue_id, &user_struct.email)? {
+            // Already has a number, we return
+            Some(number) => number,
+            None => {
+                // No number. So we assign a number
+                insert_into_queue(con, queue_id, user_struct.email.clone())?;
+                get_user_assigned_queue(con, queue_id, &user_struct.email)?
+                    .ok_or_else(|| anyhow!("Ticket for {} was not created", user_struct.email))?
+            }
+        };
+        let mut result = user_struct;
+        result.assigned_number = Some(number);
+        Ok(result)
+    })
 }
 
 /// Abandon assigned_number
-pub fn set_to_abandoned(con: &mut SqliteConnection, user_struct: UserInfo) -> AnyhowResult<()> {
-    if let Some(number) = get_user_assigned_queue(con, &user_struct.email)? {
-        diesel::update(queue::table)
-            .filter(queue::id.eq(number))
-            .set(queue::is_abandoned.eq(true))
-            .execute(con)
-            .context("Failed to set to abandoned")?;
-    }
-    Ok(())
+pub fn set_to_abandoned(
+    con: &mut SqliteConnection,
+    queue_id: i32,
+    user_struct: UserInfo,
+) -> AnyhowResult<()> {
+    con.immediate_transaction(|con| {
+        if let Some(ticket) = get_user_assigned_ticket(con, queue_id, &user_struct.email)? {
+            transition_ticket(con, &ticket, TicketStatus::Abandoned)?;
+        }
+        Ok(())
+    })
 }
 
-/// Get current queue
-pub fn get_selected_queue(con: &mut SqliteConnection) -> AnyhowResult<Option<i32>> {
-    let results = queue::table
-        .filter(queue::is_selected.eq(true))
-        .filter(queue::is_processed.eq(false))
-        .filter(queue::is_abandoned.eq(false))
+/// Get the ticket that is currently called or being served
+fn get_selected_ticket(
+    con: &mut SqliteConnection,
+    queue_id: i32,
+) -> AnyhowResult<Option<QueueRow>> {
+    let mut results = tickets::table
+        .filter(tickets::queue_id.eq(queue_id))
+        .filter(tickets::status.eq_any(status_strs(&TicketStatus::SELECTED)))
         .load::<QueueRow>(con)
         .context("Failed to query queue table")?;
 
     match results.len() {
         0 => Ok(None),
-        1 => Ok(Some(results[0].id)),
+        1 => Ok(results.pop()),
         _ => Err(anyhow!(
             "Expected 0 or 1 row. Found {} rows instead.",
             results.len()
@@ -138,20 +395,866 @@ pub fn get_selected_queue(con: &mut SqliteConnection) -> AnyhowResult<Option<i32
     }
 }
 
-/// Get abandoned and processed queue objects for given user
+/// Get current queue
+pub fn get_selected_queue(con: &mut SqliteConnection, queue_id: i32) -> AnyhowResult<Option<i32>> {
+    Ok(get_selected_ticket(con, queue_id)?.map(|ticket| ticket.id))
+}
+
+/// Get abandoned and processed queue objects for given user.
+/// No-shows are reported together with abandoned numbers
 pub fn get_abandoned_and_processed(
     con: &mut SqliteConnection,
+    queue_id: i32,
     provided_user: &str,
 ) -> AnyhowResult<(Vec<i32>, Vec<i32>)> {
-    let results = queue::table
-        .filter(queue::user.eq(provided_user))
-        .filter(queue::is_abandoned.eq(true))
-        .or_filter(queue::is_processed.eq(true))
+    let results = tickets::table
+        .filter(tickets::queue_id.eq(queue_id))
+        .filter(tickets::user.eq(provided_user))
+        .filter(tickets::status.eq_any(status_strs(&[
+            TicketStatus::Served,
+            TicketStatus::Abandoned,
+            TicketStatus::NoShow,
+        ])))
         .load::<QueueRow>(con)?;
-    let (abandoned, processed): (Vec<QueueRow>, Vec<QueueRow>) =
-        results.into_iter().partition(|item| item.is_abandoned);
+    let (processed, abandoned): (Vec<QueueRow>, Vec<QueueRow>) = results
+        .into_iter()
+        .partition(|item| item.status == TicketStatus::Served.as_str());
     Ok((
         abandoned.iter().map(|x| x.id).collect(),
         processed.iter().map(|x| x.id).collect(),
     ))
 }
+
+/// Serve the next waiting ticket. The currently selected ticket, if any, is marked as served.
+/// Runs in a single immediate transaction so that at most one row is ever selected
+pub fn call_next(con: &mut SqliteConnection, queue_id: i32) -> AnyhowResult<Option<i32>> {
+    con.immediate_transaction(|con| {
+        if let Some(selected) = get_selected_ticket(con, queue_id)? {
+            transition_ticket(con, &selected, TicketStatus::Served)?;
+        }
+        let next = tickets::table
+            .filter(tickets::queue_id.eq(queue_id))
+            .filter(tickets::status.eq(TicketStatus::Waiting.as_str()))
+            .order(tickets::id.asc())
+            .first::<QueueRow>(con)
+            .optional()
+            .context("Failed to query next waiting row")?;
+        if let Some(ticket) = next {
+            transition_ticket(con, &ticket, TicketStatus::Called)?;
+            Ok(Some(ticket.id))
+        } else {
+            Ok(None)
+        }
+    })
+}
+
+/// Move the currently selected number to the given status. Returns the number that was moved
+pub fn transition_selected(
+    con: &mut SqliteConnection,
+    queue_id: i32,
+    next_status: TicketStatus,
+) -> AnyhowResult<Option<i32>> {
+    con.immediate_transaction(|con| {
+        let selected = get_selected_ticket(con, queue_id)?;
+        if let Some(ticket) = &selected {
+            transition_ticket(con, ticket, next_status)?;
+        }
+        Ok(selected.map(|ticket| ticket.id))
+    })
+}
+
+#[derive(Queryable, Insertable)]
+#[diesel(table_name = pending_logins)]
+pub struct PendingLoginRow {
+    pub login_key: String,
+    pub csrf_token: String,
+    pub nonce: String,
+    pub pkce_verifier: String,
+    pub subapp: String,
+    pub provider: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// Store a started login. A second login from the same browser replaces the first
+pub fn insert_pending_login(
+    con: &mut SqliteConnection,
+    pending_login: PendingLoginRow,
+) -> AnyhowResult<()> {
+    diesel::replace_into(pending_logins::table)
+        .values(&pending_login)
+        .execute(con)
+        .context("Failed to insert pending login")?;
+    Ok(())
+}
+
+/// Remove and return the pending login, unless it was created before `created_after`
+pub fn take_pending_login(
+    con: &mut SqliteConnection,
+    login_key: &str,
+    created_after: NaiveDateTime,
+) -> AnyhowResult<Option<PendingLoginRow>> {
+    con.immediate_transaction(|con| {
+        let pending_login = pending_logins::table
+            .filter(pending_logins::login_key.eq(login_key))
+            .filter(pending_logins::created_at.gt(created_after))
+            .first::<PendingLoginRow>(con)
+            .optional()
+            .context("Failed to query pending login")?;
+        diesel::delete(pending_logins::table.filter(pending_logins::login_key.eq(login_key)))
+            .execute(con)
+            .context("Failed to delete pending login")?;
+        Ok(pending_login)
+    })
+}
+
+/// Delete pending logins created before the cutoff. Returns how many were deleted
+pub fn delete_pending_logins_before(
+    con: &mut SqliteConnection,
+    cutoff: NaiveDateTime,
+) -> AnyhowResult<usize> {
+    diesel::delete(pending_logins::table.filter(pending_logins::created_at.le(cutoff)))
+        .execute(con)
+        .context("Failed to delete expired pending logins")
+}
+
+#[derive(Queryable, Insertable, Clone)]
+#[diesel(table_name = user_tokens)]
+pub struct UserTokenRow {
+    pub session_id: String,
+    pub email: String,
+    pub provider: String,
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub updated_at: NaiveDateTime,
+    /// Sent back to the provider as a hint when the user logs out
+    pub id_token: Option<String>,
+    /// The `sub` and `sid` claims of the ID token, which back-channel logouts refer to
+    pub subject: Option<String>,
+    pub sid: Option<String>,
+}
+
+/// Store the tokens of a session, replacing any it had before
+pub fn upsert_user_tokens(con: &mut SqliteConnection, tokens: UserTokenRow) -> AnyhowResult<()> {
+    diesel::replace_into(user_tokens::table)
+        .values(&tokens)
+        .execute(con)
+        .context("Failed to store user tokens")?;
+    Ok(())
+}
+
+pub fn get_user_tokens(
+    con: &mut SqliteConnection,
+    session_id: &str,
+) -> AnyhowResult<Option<UserTokenRow>> {
+    user_tokens::table
+        .filter(user_tokens::session_id.eq(session_id))
+        .first::<UserTokenRow>(con)
+        .optional()
+        .context("Failed to query user tokens")
+}
+
+pub fn delete_user_tokens(con: &mut SqliteConnection, session_id: &str) -> AnyhowResult<()> {
+    diesel::delete(user_tokens::table.filter(user_tokens::session_id.eq(session_id)))
+        .execute(con)
+        .context("Failed to delete user tokens")?;
+    Ok(())
+}
+
+#[derive(Queryable, Insertable, Clone)]
+#[diesel(table_name = sessions)]
+pub struct SessionRow {
+    pub session_key: String,
+    pub email: Option<String>,
+    pub session_id: Option<String>,
+    pub state: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(AsChangeset)]
+#[diesel(table_name = sessions, treat_none_as_null = true)]
+pub struct UpdateSession<'a> {
+    pub email: Option<&'a str>,
+    pub session_id: Option<&'a str>,
+    pub state: &'a str,
+    pub updated_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+pub fn insert_session(con: &mut SqliteConnection, session: SessionRow) -> AnyhowResult<()> {
+    diesel::insert_into(sessions::table)
+        .values(&session)
+        .execute(con)
+        .context("Failed to insert session")?;
+    Ok(())
+}
+
+/// The session, unless it expired before `now`
+pub fn get_session(
+    con: &mut SqliteConnection,
+    session_key: &str,
+    now: NaiveDateTime,
+) -> AnyhowResult<Option<SessionRow>> {
+    sessions::table
+        .filter(sessions::session_key.eq(session_key))
+        .filter(sessions::expires_at.gt(now))
+        .first::<SessionRow>(con)
+        .optional()
+        .context("Failed to query session")
+}
+
+/// Returns false if there is no such session, e.g. because it was revoked
+pub fn update_session(
+    con: &mut SqliteConnection,
+    session_key: &str,
+    session: UpdateSession,
+) -> AnyhowResult<bool> {
+    let updated = diesel::update(sessions::table.filter(sessions::session_key.eq(session_key)))
+        .set(&session)
+        .execute(con)
+        .context("Failed to update session")?;
+    Ok(updated > 0)
+}
+
+pub fn update_session_expiry(
+    con: &mut SqliteConnection,
+    session_key: &str,
+    expires_at: NaiveDateTime,
+) -> AnyhowResult<()> {
+    diesel::update(sessions::table.filter(sessions::session_key.eq(session_key)))
+        .set(sessions::expires_at.eq(expires_at))
+        .execute(con)
+        .context("Failed to update session expiry")?;
+    Ok(())
+}
+
+pub fn delete_session(con: &mut SqliteConnection, session_key: &str) -> AnyhowResult<()> {
+    diesel::delete(sessions::table.filter(sessions::session_key.eq(session_key)))
+        .execute(con)
+        .context("Failed to delete session")?;
+    Ok(())
+}
+
+/// Logged in sessions of the user that have not expired, most recently created first
+pub fn get_user_sessions(
+    con: &mut SqliteConnection,
+    email: &str,
+    now: NaiveDateTime,
+) -> AnyhowResult<Vec<SessionRow>> {
+    sessions::table
+        .filter(sessions::email.eq(email))
+        .filter(sessions::session_id.is_not_null())
+        .filter(sessions::expires_at.gt(now))
+        .order(sessions::created_at.desc())
+        .load::<SessionRow>(con)
+        .context("Failed to query user sessions")
+}
+
+pub fn has_session(con: &mut SqliteConnection, session_id: &str) -> AnyhowResult<bool> {
+    diesel::select(diesel::dsl::exists(
+        sessions::table.filter(sessions::session_id.eq(session_id)),
+    ))
+    .get_result(con)
+    .context("Failed to query session")
+}
+
+/// Delete the user's sessions along with their tokens, only the one with `session_id` if given.
+/// Returns the ids of the deleted sessions
+pub fn revoke_sessions(
+    con: &mut SqliteConnection,
+    email: &str,
+    session_id: Option<&str>,
+) -> AnyhowResult<Vec<String>> {
+    con.immediate_transaction(|con| {
+        let mut query = sessions::table
+            .filter(sessions::email.eq(email))
+            .select(sessions::session_id)
+            .into_boxed();
+        if let Some(session_id) = session_id {
+            query = query.filter(sessions::session_id.eq(session_id));
+        }
+        let session_ids: Vec<String> = query
+            .load::<Option<String>>(con)
+            .context("Failed to query user sessions")?
+            .into_iter()
+            .flatten()
+            .collect();
+        let mut query = diesel::delete(sessions::table)
+            .filter(sessions::email.eq(email))
+            .into_boxed();
+        if let Some(session_id) = session_id {
+            query = query.filter(sessions::session_id.eq(session_id));
+        }
+        query.execute(con).context("Failed to delete sessions")?;
+        diesel::delete(user_tokens::table.filter(user_tokens::session_id.eq_any(&session_ids)))
+            .execute(con)
+            .context("Failed to delete user tokens")?;
+        Ok(session_ids)
+    })
+}
+
+/// Delete the sessions logged in at the provider with the given `sub` and `sid` claims, along
+/// with their tokens. Only the claims that are given have to match.
+/// Returns the ids of the deleted sessions
+pub fn revoke_provider_sessions(
+    con: &mut SqliteConnection,
+    provider: &str,
+    subject: Option<&str>,
+    sid: Option<&str>,
+) -> AnyhowResult<Vec<String>> {
+    if subject.is_none() && sid.is_none() {
+        return Err(anyhow!("Expected a subject or sid to revoke sessions by"));
+    }
+    con.immediate_transaction(|con| {
+        let mut query = user_tokens::table
+            .filter(user_tokens::provider.eq(provider))
+            .select(user_tokens::session_id)
+            .into_boxed();
+        if let Some(subject) = subject {
+            query = query.filter(user_tokens::subject.eq(subject));
+        }
+        if let Some(sid) = sid {
+            query = query.filter(user_tokens::sid.eq(sid));
+        }
+        let session_ids = query
+            .load::<String>(con)
+            .context("Failed to query user tokens")?;
+        diesel::delete(sessions::table.filter(sessions::session_id.eq_any(&session_ids)))
+            .execute(con)
+            .context("Failed to delete sessions")?;
+        diesel::delete(user_tokens::table.filter(user_tokens::session_id.eq_any(&session_ids)))
+            .execute(con)
+            .context("Failed to delete user tokens")?;
+        Ok(session_ids)
+    })
+}
+
+/// Delete sessions that expired before the cutoff, along with their tokens. Returns how many
+/// were deleted
+pub fn delete_sessions_before(
+    con: &mut SqliteConnection,
+    cutoff: NaiveDateTime,
+) -> AnyhowResult<usize> {
+    con.immediate_transaction(|con| {
+        let session_ids: Vec<String> = sessions::table
+            .filter(sessions::expires_at.le(cutoff))
+            .select(sessions::session_id)
+            .load::<Option<String>>(con)
+            .context("Failed to query expired sessions")?
+            .into_iter()
+            .flatten()
+            .collect();
+        let deleted = diesel::delete(sessions::table.filter(sessions::expires_at.le(cutoff)))
+            .execute(con)
+            .context("Failed to delete expired sessions")?;
+        diesel::delete(user_tokens::table.filter(user_tokens::session_id.eq_any(&session_ids)))
+            .execute(con)
+            .context("Failed to delete user tokens")?;
+        Ok(deleted)
+    })
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = users)]
+struct InsertUser<'a> {
+    email: &'a str,
+    created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = user_roles)]
+struct InsertUserRole<'a> {
+    email: &'a str,
+    role: &'a str,
+    granted_by: &'a str,
+    granted_at: NaiveDateTime,
+}
+
+/// Record that the user exists, e.g. on their first login
+pub fn insert_user_if_missing(con: &mut SqliteConnection, email: &str) -> AnyhowResult<()> {
+    diesel::insert_or_ignore_into(users::table)
+        .values(&InsertUser {
+            email,
+            created_at: Utc::now().naive_utc(),
+        })
+        .execute(con)
+        .context("Failed to insert user")?;
+    Ok(())
+}
+
+pub fn get_user_roles(con: &mut SqliteConnection, email: &str) -> AnyhowResult<Vec<Role>> {
+    user_roles::table
+        .filter(user_roles::email.eq(email))
+        .select(user_roles::role)
+        .load::<String>(con)
+        .context("Failed to query user roles")?
+        .iter()
+        .map(|role| Role::from_str(role).map_err(|e| anyhow!(e)))
+        .collect()
+}
+
+/// Every known user with their roles, ordered by email
+pub fn get_users_with_roles(con: &mut SqliteConnection) -> AnyhowResult<Vec<UserRoles>> {
+    let rows = users::table
+        .left_join(user_roles::table)
+        .select((users::email, user_roles::role.nullable()))
+        .order((users::email, user_roles::role))
+        .load::<(String, Option<String>)>(con)
+        .context("Failed to query users")?;
+    let mut users: Vec<UserRoles> = Vec::new();
+    for (email, role) in rows {
+        if users.last().map(|user| &user.email) != Some(&email) {
+            users.push(UserRoles {
+                email,
+                roles: Vec::new(),
+            });
+        }
+        if let (Some(user), Some(role)) = (users.last_mut(), role) {
+            user.roles
+                .push(Role::from_str(&role).map_err(|e| anyhow!(e))?);
+        }
+    }
+    Ok(users)
+}
+
+/// Grant the role, creating the user if they never logged in. Granting twice is a no-op
+pub fn grant_role(
+    con: &mut SqliteConnection,
+    email: &str,
+    role: Role,
+    granted_by: &str,
+) -> AnyhowResult<()> {
+    con.immediate_transaction(|con| {
+        insert_user_if_missing(con, email)?;
+        diesel::insert_or_ignore_into(user_roles::table)
+            .values(&InsertUserRole {
+                email,
+                role: role.as_str(),
+                granted_by,
+                granted_at: Utc::now().naive_utc(),
+            })
+            .execute(con)
+            .context("Failed to grant role")?;
+        Ok(())
+    })
+}
+
+/// Revoke the role. Returns whether the user had it.
+/// The last admin cannot be revoked, so there is always someone left to grant roles
+pub fn revoke_role(con: &mut SqliteConnection, email: &str, role: Role) -> AnyhowResult<bool> {
+    con.immediate_transaction(|con| {
+        if role == Role::Admin {
+            let admins = user_roles::table
+                .filter(user_roles::role.eq(Role::Admin.as_str()))
+                .select(user_roles::email)
+                .load::<String>(con)
+                .context("Failed to query admins")?;
+            if admins.len() == 1 && admins[0] == email {
+                return Err(LastAdmin.into());
+            }
+        }
+        let revoked = diesel::delete(
+            user_roles::table
+                .filter(user_roles::email.eq(email))
+                .filter(user_roles::role.eq(role.as_str())),
+        )
+        .execute(con)
+        .context("Failed to revoke role")?;
+        Ok(revoked > 0)
+    })
+}
+
+/// A row of the `casbin_rule` table. Unused trailing values are empty strings
+#[derive(Queryable, Clone, Debug)]
+pub struct PolicyRow {
+    pub id: i32,
+    pub ptype: String,
+    pub v0: String,
+    pub v1: String,
+    pub v2: String,
+    pub v3: String,
+    pub v4: String,
+    pub v5: String,
+}
+
+#[derive(Insertable, AsChangeset)]
+#[diesel(table_name = casbin_rule)]
+pub struct InsertPolicy<'a> {
+    pub ptype: &'a str,
+    pub v0: &'a str,
+    pub v1: &'a str,
+    pub v2: &'a str,
+}
+
+pub fn get_policies(con: &mut SqliteConnection) -> AnyhowResult<Vec<PolicyRow>> {
+    casbin_rule::table
+        .order(casbin_rule::id)
+        .load::<PolicyRow>(con)
+        .context("Failed to query policies")
+}
+
+pub fn get_policy(con: &mut SqliteConnection, id: i32) -> AnyhowResult<Option<PolicyRow>> {
+    casbin_rule::table
+        .filter(casbin_rule::id.eq(id))
+        .first::<PolicyRow>(con)
+        .optional()
+        .context("Failed to query policy")
+}
+
+/// The id of an identical policy, if there is one
+pub fn find_policy(con: &mut SqliteConnection, policy: &InsertPolicy) -> AnyhowResult<Option<i32>> {
+    casbin_rule::table
+        .filter(casbin_rule::ptype.eq(policy.ptype))
+        .filter(casbin_rule::v0.eq(policy.v0))
+        .filter(casbin_rule::v1.eq(policy.v1))
+        .filter(casbin_rule::v2.eq(policy.v2))
+        .select(casbin_rule::id)
+        .first::<i32>(con)
+        .optional()
+        .context("Failed to query policy")
+}
+
+/// Insert the policy and return its id
+pub fn insert_policy(con: &mut SqliteConnection, policy: InsertPolicy) -> AnyhowResult<i32> {
+    con.immediate_transaction(|con| {
+        diesel::insert_into(casbin_rule::table)
+            .values(&policy)
+            .execute(con)
+            .context("Failed to insert policy")?;
+        find_policy(con, &policy)?.ok_or_else(|| anyhow!("Inserted policy not found"))
+    })
+}
+
+/// Returns whether there was a policy with the id
+pub fn update_policy(
+    con: &mut SqliteConnection,
+    id: i32,
+    policy: InsertPolicy,
+) -> AnyhowResult<bool> {
+    let updated = diesel::update(casbin_rule::table.filter(casbin_rule::id.eq(id)))
+        .set(&policy)
+        .execute(con)
+        .context("Failed to update policy")?;
+    Ok(updated > 0)
+}
+
+/// Returns whether there was a policy with the id
+pub fn delete_policy(con: &mut SqliteConnection, id: i32) -> AnyhowResult<bool> {
+    let deleted = diesel::delete(casbin_rule::table.filter(casbin_rule::id.eq(id)))
+        .execute(con)
+        .context("Failed to delete policy")?;
+    Ok(deleted > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use common::IllegalTransition;
+
+    fn create_test_queue(con: &mut SqliteConnection, slug: &str) -> i32 {
+        let request = CreateQueueRequest {
+            slug: slug.to_string(),
+            name: slug.to_string(),
+            description: String::new(),
+        };
+        create_queue(con, request, "owner@example.com").unwrap().id
+    }
+
+    fn user(email: &str) -> UserInfo {
+        UserInfo {
+            email: email.to_string(),
+            is_logged_in: true,
+            is_admin: false,
+            is_observer: false,
+            assigned_number: None,
+            roles: Vec::new(),
+        }
+    }
+
+    /// Take a number for the user and return it
+    fn join(con: &mut SqliteConnection, queue_id: i32, email: &str) -> i32 {
+        get_or_insert(con, queue_id, user(email))
+            .unwrap()
+            .assigned_number
+            .unwrap()
+    }
+
+    fn ticket(con: &mut SqliteConnection, id: i32) -> QueueRow {
+        tickets::table.find(id).first::<QueueRow>(con).unwrap()
+    }
+
+    fn selected_count(con: &mut SqliteConnection, queue_id: i32) -> i64 {
+        tickets::table
+            .filter(tickets::queue_id.eq(queue_id))
+            .filter(tickets::status.eq_any(status_strs(&TicketStatus::SELECTED)))
+            .count()
+            .get_result(con)
+            .unwrap()
+    }
+
+    #[test]
+    fn call_next_on_an_empty_queue_calls_nobody() {
+        let con = &mut test_support::connection();
+        let queue_id = create_test_queue(con, "test-queue");
+        assert_eq!(call_next(con, queue_id).unwrap(), None);
+        assert_eq!(get_selected_queue(con, queue_id).unwrap(), None);
+    }
+
+    #[test]
+    fn call_next_serves_the_selected_number_and_calls_the_next() {
+        let con = &mut test_support::connection();
+        let queue_id = create_test_queue(con, "test-queue");
+        let first = join(con, queue_id, "first@example.com");
+        let second = join(con, queue_id, "second@example.com");
+
+        assert_eq!(call_next(con, queue_id).unwrap(), Some(first));
+        assert_eq!(selected_count(con, queue_id), 1);
+        assert_eq!(call_next(con, queue_id).unwrap(), Some(second));
+        assert_eq!(selected_count(con, queue_id), 1);
+        assert_eq!(ticket(con, first).status().unwrap(), TicketStatus::Served);
+        assert_eq!(get_selected_queue(con, queue_id).unwrap(), Some(second));
+
+        // The last number is served even though nobody is left to call
+        assert_eq!(call_next(con, queue_id).unwrap(), None);
+        assert_eq!(selected_count(con, queue_id), 0);
+        assert_eq!(ticket(con, second).status().unwrap(), TicketStatus::Served);
+    }
+
+    #[test]
+    fn queues_select_independently() {
+        let con = &mut test_support::connection();
+        let queue_id = create_test_queue(con, "test-queue");
+        let other_queue_id = create_test_queue(con, "other-queue");
+        let first = join(con, queue_id, "user@example.com");
+        let other = join(con, other_queue_id, "user@example.com");
+
+        assert_eq!(call_next(con, queue_id).unwrap(), Some(first));
+        assert_eq!(call_next(con, other_queue_id).unwrap(), Some(other));
+        assert_eq!(get_selected_queue(con, queue_id).unwrap(), Some(first));
+        assert_eq!(selected_count(con, queue_id), 1);
+    }
+
+    #[test]
+    fn transition_selected_moves_only_the_selected_number() {
+        let con = &mut test_support::connection();
+        let queue_id = create_test_queue(con, "test-queue");
+        assert_eq!(
+            transition_selected(con, queue_id, TicketStatus::Serving).unwrap(),
+            None
+        );
+
+        let first = join(con, queue_id, "first@example.com");
+        let second = join(con, queue_id, "second@example.com");
+        call_next(con, queue_id).unwrap();
+        assert_eq!(
+            transition_selected(con, queue_id, TicketStatus::Serving).unwrap(),
+            Some(first)
+        );
+        assert_eq!(selected_count(con, queue_id), 1);
+        // Serving numbers can no longer be no-shows
+        let error = transition_selected(con, queue_id, TicketStatus::NoShow).unwrap_err();
+        assert!(error.downcast_ref::<IllegalTransition>().is_some());
+        assert_eq!(
+            transition_selected(con, queue_id, TicketStatus::Served).unwrap(),
+            Some(first)
+        );
+        assert_eq!(selected_count(con, queue_id), 0);
+        assert_eq!(ticket(con, second).status().unwrap(), TicketStatus::Waiting);
+    }
+
+    #[test]
+    fn users_hold_one_active_number_per_queue() {
+        let con = &mut test_support::connection();
+        let queue_id = create_test_queue(con, "test-queue");
+        let number = join(con, queue_id, "user@example.com");
+        assert_eq!(join(con, queue_id, "user@example.com"), number);
+        assert!(insert_into_queue(con, queue_id, "user@example.com".to_string()).is_err());
+
+        // The number is still active once called
+        call_next(con, queue_id).unwrap();
+        assert!(insert_into_queue(con, queue_id, "user@example.com".to_string()).is_err());
+
+        // Once served, the user may take a new number
+        transition_selected(con, queue_id, TicketStatus::Served).unwrap();
+        let next_number = join(con, queue_id, "user@example.com");
+        assert_ne!(next_number, number);
+        let (abandoned, done) =
+            get_abandoned_and_processed(con, queue_id, "user@example.com").unwrap();
+        assert!(abandoned.is_empty());
+        assert_eq!(done, [number]);
+    }
+
+    #[test]
+    fn people_ahead_counts_waiting_numbers_before_the_user() {
+        let con = &mut test_support::connection();
+        let queue_id = create_test_queue(con, "test-queue");
+        let other_queue_id = create_test_queue(con, "other-queue");
+        join(con, queue_id, "first@example.com");
+        join(con, queue_id, "second@example.com");
+        join(con, other_queue_id, "elsewhere@example.com");
+        join(con, queue_id, "user@example.com");
+        join(con, queue_id, "behind@example.com");
+        let people_ahead =
+            |con: &mut SqliteConnection, email| get_people_ahead(con, queue_id, email).unwrap();
+        assert_eq!(people_ahead(con, "first@example.com"), Some(0));
+        assert_eq!(people_ahead(con, "user@example.com"), Some(2));
+
+        // Numbers that left the queue or were called are no longer ahead
+        set_to_abandoned(con, queue_id, user("second@example.com")).unwrap();
+        assert_eq!(people_ahead(con, "user@example.com"), Some(1));
+        call_next(con, queue_id).unwrap();
+        assert_eq!(people_ahead(con, "user@example.com"), Some(0));
+        assert_eq!(people_ahead(con, "behind@example.com"), Some(1));
+    }
+
+    #[test]
+    fn people_ahead_is_only_known_while_waiting() {
+        let con = &mut test_support::connection();
+        let queue_id = create_test_queue(con, "test-queue");
+        join(con, queue_id, "user@example.com");
+        assert_eq!(get_people_ahead(con, queue_id, ANONYMOUS).unwrap(), None);
+        assert_eq!(
+            get_people_ahead(con, queue_id, "stranger@example.com").unwrap(),
+            None
+        );
+        call_next(con, queue_id).unwrap();
+        assert_eq!(
+            get_people_ahead(con, queue_id, "user@example.com").unwrap(),
+            None
+        );
+    }
+
+    /// Record a finished number for the user that took `service_seconds` at the counter,
+    /// `finished_ago` before now
+    fn finish(
+        con: &mut SqliteConnection,
+        queue_id: i32,
+        email: &str,
+        status: TicketStatus,
+        service_seconds: i64,
+        finished_ago: i64,
+    ) {
+        let id = join(con, queue_id, email);
+        let served_at = Utc::now().naive_utc() - chrono::Duration::seconds(finished_ago);
+        diesel::update(tickets::table.find(id))
+            .set((
+                tickets::status.eq(status.as_str()),
+                tickets::called_at.eq(served_at - chrono::Duration::seconds(service_seconds)),
+                tickets::served_at.eq(served_at),
+            ))
+            .execute(con)
+            .unwrap();
+    }
+
+    #[test]
+    fn average_service_time_needs_history() {
+        let con = &mut test_support::connection();
+        let queue_id = create_test_queue(con, "test-queue");
+        assert_eq!(get_average_service_seconds(con, queue_id).unwrap(), None);
+        assert_eq!(
+            get_estimated_wait_seconds(con, queue_id, Some(3)).unwrap(),
+            None
+        );
+
+        // A number called and served right away still counts
+        join(con, queue_id, "user@example.com");
+        call_next(con, queue_id).unwrap();
+        call_next(con, queue_id).unwrap();
+        assert_eq!(get_average_service_seconds(con, queue_id).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn average_service_time_covers_a_short_history() {
+        let con = &mut test_support::connection();
+        let queue_id = create_test_queue(con, "test-queue");
+        let other_queue_id = create_test_queue(con, "other-queue");
+        for (i, service_seconds) in [60, 120, 180].into_iter().enumerate() {
+            let email = format!("user{}@example.com", i);
+            finish(
+                con,
+                queue_id,
+                &email,
+                TicketStatus::Served,
+                service_seconds,
+                0,
+            );
+        }
+        finish(
+            con,
+            other_queue_id,
+            "elsewhere@example.com",
+            TicketStatus::Served,
+            900,
+            0,
+        );
+        assert_eq!(
+            get_average_service_seconds(con, queue_id).unwrap(),
+            Some(120)
+        );
+        // Nobody at the counter, so only the people ahead count
+        assert_eq!(
+            get_estimated_wait_seconds(con, queue_id, Some(2)).unwrap(),
+            Some(240)
+        );
+        assert_eq!(
+            get_estimated_wait_seconds(con, queue_id, None).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn average_service_time_ignores_numbers_that_were_not_served() {
+        let con = &mut test_support::connection();
+        let queue_id = create_test_queue(con, "test-queue");
+        finish(
+            con,
+            queue_id,
+            "served@example.com",
+            TicketStatus::Served,
+            60,
+            0,
+        );
+        finish(
+            con,
+            queue_id,
+            "gone@example.com",
+            TicketStatus::Abandoned,
+            900,
+            0,
+        );
+        finish(
+            con,
+            queue_id,
+            "absent@example.com",
+            TicketStatus::NoShow,
+            900,
+            0,
+        );
+        assert_eq!(
+            get_average_service_seconds(con, queue_id).unwrap(),
+            Some(60)
+        );
+    }
+
+    #[test]
+    fn average_service_time_covers_the_latest_window() {
+        let con = &mut test_support::connection();
+        let queue_id = create_test_queue(con, "test-queue");
+        // Served long ago and slowly, so they would skew the average
+        for i in 0..5 {
+            let email = format!("early{}@example.com", i);
+            finish(con, queue_id, &email, TicketStatus::Served, 3600, 7200);
+        }
+        for i in 0..SERVICE_TIME_WINDOW {
+            let email = format!("recent{}@example.com", i);
+            finish(con, queue_id, &email, TicketStatus::Served, 30, i);
+        }
+        assert_eq!(
+            get_average_service_seconds(con, queue_id).unwrap(),
+            Some(30)
+        );
+    }
+}
@@ -0,0 +1,294 @@
+use crate::database;
+use crate::sessions::RevocationWatch;
+use actix_web_lab::sse;
+use common::ServerSentData;
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    SqliteConnection,
+};
+use log::{debug, error};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// How many events a slow subscriber can fall behind before it skips ahead
+const EVENT_BUFFER: usize = 16;
+/// How many past events per subapp are kept around for clients resuming with Last-Event-ID
+const EVENT_HISTORY: usize = 64;
+/// How often an idle stream gets a keep-alive comment
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// What happened to a queue. Subscribers re-read their own view of the queue on every event
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueEvent {
+    Joined,
+    Abandoned,
+    Called,
+    Serving,
+    Served,
+    NoShow,
+}
+
+/// A queue event tagged with its position in the event stream
+#[derive(Clone, Copy, Debug)]
+pub struct SequencedEvent {
+    pub id: u64,
+    pub event: QueueEvent,
+}
+
+/// Everything a new subscriber needs: events it missed, and a receiver for everything after
+pub struct Subscription {
+    pub missed: Vec<SequencedEvent>,
+    pub latest_id: u64,
+    pub receiver: broadcast::Receiver<SequencedEvent>,
+}
+
+struct Channel {
+    sender: broadcast::Sender<SequencedEvent>,
+    history: VecDeque<SequencedEvent>,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Channel {
+            sender: broadcast::channel(EVENT_BUFFER).0,
+            history: VecDeque::with_capacity(EVENT_HISTORY),
+        }
+    }
+}
+
+#[derive(Default)]
+struct EventBusState {
+    last_id: u64,
+    channels: HashMap<String, Channel>,
+}
+
+/// In-process event bus with one broadcast channel per subapp.
+/// Event ids are shared across subapps so they only ever go up
+#[derive(Default)]
+pub struct EventBus {
+    state: Mutex<EventBusState>,
+}
+
+impl EventBus {
+    /// Notify every subscriber of the subapp, and remember the event for clients that reconnect
+    pub fn publish(&self, subapp: &str, event: QueueEvent) {
+        let mut state = self.state.lock().expect("Event bus lock poisoned");
+        state.last_id += 1;
+        let event = SequencedEvent {
+            id: state.last_id,
+            event,
+        };
+        let channel = state.channels.entry(subapp.to_string()).or_default();
+        if channel.history.len() == EVENT_HISTORY {
+            channel.history.pop_front();
+        }
+        channel.history.push_back(event);
+        // No receivers is fine, the event is still in the history
+        let _ = channel.sender.send(event);
+    }
+
+    /// Subscribe to a subapp. Events after `last_event_id` that are still in the history are
+    /// returned so the caller can replay them before anything from the receiver
+    pub fn subscribe(&self, subapp: &str, last_event_id: Option<u64>) -> Subscription {
+        let mut state = self.state.lock().expect("Event bus lock poisoned");
+        let latest_id = state.last_id;
+        let channel = state.channels.entry(subapp.to_string()).or_default();
+        let missed = match last_event_id {
+            // Ids from before a restart cannot be resumed, the snapshot has to do
+            Some(last_event_id) if last_event_id <= latest_id => channel
+                .history
+                .iter()
+                .filter(|event| event.id > last_event_id)
+                .copied()
+                .collect(),
+            _ => Vec::new(),
+        };
+        Subscription {
+            missed,
+            latest_id,
+            receiver: channel.sender.subscribe(),
+        }
+    }
+}
+
+/// Replay missed events and send the user a snapshot of the queue straight away, then an event
+/// and a fresh snapshot for every event on the queue. Idle streams get keep-alive comments.
+/// Returns once the client disconnects or the user's session is revoked
+pub async fn forward_to_subscriber(
+    db_connection_pool: Pool<ConnectionManager<SqliteConnection>>,
+    queue_id: i32,
+    user: String,
+    subscription: Subscription,
+    mut revocation: Option<RevocationWatch>,
+    sender: sse::Sender,
+) {
+    let Subscription {
+        missed,
+        latest_id,
+        mut receiver,
+    } = subscription;
+    for event in missed {
+        if send_event(&sender, event).await.is_err() {
+            return;
+        }
+    }
+    // The first snapshot carries the id the client is up to date with
+    let mut snapshot_id = Some(latest_id);
+    let mut keep_alive = tokio::time::interval(KEEP_ALIVE_INTERVAL);
+    // The first tick completes immediately
+    keep_alive.tick().await;
+    loop {
+        let snapshot = match db_connection_pool.get() {
+            Ok(mut db_connection) => {
+                database::get_server_sent_data(&mut db_connection, queue_id, &user)
+            }
+            Err(e) => Err(e.into()),
+        };
+        match snapshot {
+            Ok(snapshot) => {
+                if send_snapshot(&sender, &snapshot, snapshot_id.take())
+                    .await
+                    .is_err()
+                {
+                    debug!("Subscriber {} disconnected", user);
+                    return;
+                }
+            }
+            Err(e) => error!("Failed to build snapshot for {}: {:#}", user, e),
+        }
+        let event = loop {
+            tokio::select! {
+                event = receiver.recv() => break event,
+                _ = keep_alive.tick() => {
+                    // Also how we notice clients that went away while the queue was quiet
+                    if sender.send(sse::Event::Comment("keep-alive".into())).await.is_err() {
+                        debug!("Subscriber {} disconnected", user);
+                        return;
+                    }
+                }
+                _ = wait_for_revocation(&mut revocation) => {
+                    debug!("Closing stream of {}, the session was revoked", user);
+                    return;
+                }
+            }
+        };
+        match event {
+            Ok(event) => {
+                debug!("Queue {} event {:?}", queue_id, event);
+                if send_event(&sender, event).await.is_err() {
+                    debug!("Subscriber {} disconnected", user);
+                    return;
+                }
+            }
+            // The snapshot that follows brings the client up to date
+            Err(RecvError::Lagged(skipped)) => debug!("Subscriber {} skipped {}", user, skipped),
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+async fn wait_for_revocation(revocation: &mut Option<RevocationWatch>) {
+    match revocation {
+        Some(revocation) => revocation.revoked().await,
+        // Anonymous streams have no session to revoke
+        None => std::future::pending().await,
+    }
+}
+
+/// Events carry the id, so a reconnecting browser resumes from the last event it saw
+async fn send_event(sender: &sse::Sender, event: SequencedEvent) -> Result<(), sse::SendError> {
+    let mut data = sse::Data::new(
+        serde_json::to_string(&event.event).expect("Expected to be able to serialise as string"),
+    );
+    data.set_event("queue_event");
+    data.set_id(event.id.to_string());
+    sender.send(data).await
+}
+
+async fn send_snapshot(
+    sender: &sse::Sender,
+    message: &ServerSentData,
+    id: Option<u64>,
+) -> Result<(), sse::SendError> {
+    let mut data = sse::Data::new(
+        serde_json::to_string(message).expect("Expected to be able to serialise as string"),
+    );
+    data.set_event("data");
+    if let Some(id) = id {
+        data.set_id(id.to_string());
+    }
+    sender.send(data).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(events: &[SequencedEvent]) -> Vec<u64> {
+        events.iter().map(|event| event.id).collect()
+    }
+
+    #[test]
+    fn replays_events_after_the_last_event_id() {
+        let event_bus = EventBus::default();
+        for _ in 0..3 {
+            event_bus.publish("demo", QueueEvent::Joined);
+        }
+        let subscription = event_bus.subscribe("demo", Some(1));
+        assert_eq!(ids(&subscription.missed), [2, 3]);
+        assert_eq!(subscription.latest_id, 3);
+        assert!(event_bus.subscribe("demo", Some(3)).missed.is_empty());
+        assert!(event_bus.subscribe("demo", None).missed.is_empty());
+    }
+
+    #[test]
+    fn replays_only_the_history_for_ids_older_than_it() {
+        let event_bus = EventBus::default();
+        let published = EVENT_HISTORY as u64 + 6;
+        for _ in 0..published {
+            event_bus.publish("demo", QueueEvent::Joined);
+        }
+        let subscription = event_bus.subscribe("demo", Some(1));
+        let expected = (published - EVENT_HISTORY as u64 + 1..=published).collect::<Vec<_>>();
+        assert_eq!(ids(&subscription.missed), expected);
+    }
+
+    #[test]
+    fn replays_nothing_for_ids_it_never_issued() {
+        let event_bus = EventBus::default();
+        event_bus.publish("demo", QueueEvent::Joined);
+        event_bus.publish("demo", QueueEvent::Called);
+        // e.g. an id from before a restart
+        let subscription = event_bus.subscribe("demo", Some(1000));
+        assert!(subscription.missed.is_empty());
+        assert_eq!(subscription.latest_id, 2);
+    }
+
+    #[test]
+    fn ids_go_up_across_subapps() {
+        let event_bus = EventBus::default();
+        event_bus.publish("first", QueueEvent::Joined);
+        event_bus.publish("second", QueueEvent::Joined);
+        event_bus.publish("first", QueueEvent::Called);
+        assert_eq!(ids(&event_bus.subscribe("first", Some(0)).missed), [1, 3]);
+        assert_eq!(ids(&event_bus.subscribe("second", Some(0)).missed), [2]);
+        assert_eq!(event_bus.subscribe("third", Some(0)).latest_id, 3);
+    }
+
+    #[test]
+    fn receiver_gets_events_after_subscribing() {
+        let event_bus = EventBus::default();
+        event_bus.publish("demo", QueueEvent::Joined);
+        let mut subscription = event_bus.subscribe("demo", Some(0));
+        event_bus.publish("other", QueueEvent::Joined);
+        event_bus.publish("demo", QueueEvent::Served);
+        let event = subscription.receiver.try_recv().unwrap();
+        assert_eq!(event.id, 3);
+        assert!(matches!(event.event, QueueEvent::Served));
+        assert!(subscription.receiver.try_recv().is_err());
+    }
+}
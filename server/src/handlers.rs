@@ -1,16 +1,41 @@
 use crate::{
-    auth::{get_oidc_login, is_authorised, token_exchange_internal, Callback},
-    AppState, SseSender,
+    auth::{get_oidc_login, token_exchange_internal, AuthenticatedUser, Callback},
+    authz::{InvalidPolicy, PolicyConflict},
+    events::{forward_to_subscriber, QueueEvent},
+    logout::{
+        get_end_session_url, verify_logout_token, BackchannelLogout, InvalidLogoutToken,
+        PostLogoutRedirect,
+    },
+    middleware::{has_csrf_header, MissingCsrfHeader},
+    oidc::ProviderUnavailable,
+    remote,
+    roles::{normalise_email, LastAdmin},
+    tokens::SESSION_ID_KEY,
+    AppState,
+};
+use crate::{
+    database,
+    database::{QueueRecord, UserTokenRow},
+    pending_logins::PendingLogin,
 };
-use crate::{database, OidcMetadata};
 use actix_session::Session;
-use actix_web::error::ErrorInternalServerError;
-use actix_web::{get, post, Responder, Result as ActixResult};
+use actix_web::error::{
+    ErrorBadGateway, ErrorBadRequest, ErrorConflict, ErrorInternalServerError, ErrorNotFound,
+    ErrorServiceUnavailable, ErrorUnauthorized,
+};
+use actix_web::{delete, get, post, put, route, Responder, Result as ActixResult};
 use actix_web::{web, HttpRequest, HttpResponse};
 use actix_web_lab::sse;
 use anyhow::Result as AnyhowResult;
-use common::{ServerSentData, UserInfo};
-use log::{error, info};
+use common::{
+    validate_slug, CreateQueueRequest, IllegalTransition, LoginProvider, LogoutResponse, Policy,
+    PolicyRequest, QueueDetails, QueueMember, QueueRole, QueueSummary, RevokedSessions, Role,
+    ServerSentData, SessionSummary, TicketStatus, UserInfo, UserRoles,
+};
+use diesel::SqliteConnection;
+use log::{error, info, warn};
+use openidconnect::url::Url;
+use std::str::FromStr;
 use std::time::Duration;
 use uuid::Uuid;
 
@@ -23,23 +48,14 @@ pub async fn spa_index() -> ActixResult<actix_files::NamedFile> {
 }
 
 #[get("/api/hello")]
-async fn hello(
-    app_state: web::Data<AppState>,
-    session: Session,
-    request: HttpRequest,
-) -> ActixResult<String> {
-    let user = is_authorised(&session, &app_state.authz_enforcer, request)?;
+async fn hello(user: AuthenticatedUser) -> ActixResult<String> {
     Ok(format!("hello there {}", user.email))
 }
 
 /// Endpoint to get user info, e.g. his username, etc.
 #[get("/public/get_user_info2")]
-async fn get_user_info2(
-    app_state: web::Data<AppState>,
-    session: Session,
-    request: HttpRequest,
-) -> ActixResult<web::Json<UserInfo>> {
-    let mut user = is_authorised(&session, &app_state.authz_enforcer, request)?;
+async fn get_user_info2(user: AuthenticatedUser) -> ActixResult<web::Json<UserInfo>> {
+    let user = user.into_inner();
     info!("{:#?}", user);
 
     // Get user assigned queue number if it exists
@@ -59,21 +75,8 @@ async fn token_exchange(
     req_body: web::Query<Callback>,
     app_state: web::Data<AppState>,
     session: Session,
-    request: HttpRequest,
 ) -> ActixResult<HttpResponse> {
-    is_authorised(&session, &app_state.authz_enforcer, request)?;
-    match token_exchange_internal(
-        app_state.client_id.clone(),
-        app_state.client_secret.clone(),
-        req_body,
-        app_state,
-        session,
-    )
-    .await
-    {
-        Ok(value) => Ok(value),
-        Err(e) => Err(ErrorInternalServerError(e.to_string())),
-    }
+    wrap_oidc_error(token_exchange_internal(req_body, app_state, session).await)
 }
 
 /// if anonymous user that is not logged in, we generate a session with key "anonuser" with a uuid to track him.
@@ -83,176 +86,772 @@ async fn login(
     app_state: web::Data<AppState>,
     session: Session,
     info: web::Path<(String,)>,
-    request: HttpRequest,
 ) -> ActixResult<HttpResponse> {
     let subapp = info.into_inner().0;
-    let base_url = format!("/{}", subapp);
     // authorisation of public endpoints unnecessary? but good hygiene I guess
-    is_authorised(&session, &app_state.authz_enforcer, request)?;
-    // if user already logged in, we skip this flow
-    if let Some(email) = session.get::<String>("user")? {
-        if !email.is_empty() {
-            info!("User already logged in");
-            return Ok(HttpResponse::TemporaryRedirect()
-                .insert_header(("Location", base_url))
-                .body("Already logged in. Redirecting"));
-        }
-    }
+    let provider = app_state.oidc_config.default_provider().name.clone();
+    start_login(&app_state, &session, subapp, provider).await
+}
 
-    let anonuser = "anonuser";
-    let anonuserid = if let Some(anonuserid) = session.get::<String>(anonuser)? {
-        // info!("Anonymous user already has a session_id: {}", anonuserid);
-        anonuserid
-    } else {
-        info!("Anonymous user does NOT have a session_id. Generating one for him");
-        let uuid = Uuid::new_v4().to_string();
-        session.insert(anonuser, uuid.clone())?;
-        uuid
-    };
-    let (url, csrf_token, nonce) =
-        get_oidc_login(app_state.client_id.clone(), app_state.client_secret.clone()).await;
-    let mut session_oidc_state = app_state.session_oidc_state.lock().await;
-    // .expect("Expected to be able to lock mutex");
-    let oidc_metadata = OidcMetadata {
-        csrf_token,
-        nonce,
-        subapp,
-    };
-    (*session_oidc_state).insert(anonuserid, oidc_metadata);
-    Ok(HttpResponse::TemporaryRedirect()
-        .insert_header(("Location", url.to_string()))
-        .body("Redirecting to login"))
+/// Same as `login`, against the named provider
+#[get("/public/{subapp}/trigger_login/{provider}")]
+async fn login_with_provider(
+    app_state: web::Data<AppState>,
+    session: Session,
+    info: web::Path<(String, String)>,
+) -> ActixResult<HttpResponse> {
+    let (subapp, provider) = info.into_inner();
+    start_login(&app_state, &session, subapp, provider).await
 }
 
-#[get("/api/{subapp}/trigger_logout")]
+/// The providers to show a login button for
+#[get("/public/login_providers")]
+async fn get_login_providers(
+    app_state: web::Data<AppState>,
+) -> ActixResult<web::Json<Vec<LoginProvider>>> {
+    Ok(web::Json(app_state.oidc_config.login_providers()))
+}
+
+/// Log out here, then at the provider too if it supports RP-initiated logout, so the next login
+/// asks for credentials again. A POST, so the anti-forgery header keeps other sites from logging
+/// users out. The frontend sends the browser to the returned URL
+#[post("/api/{subapp}/trigger_logout")]
 async fn logout(
     app_state: web::Data<AppState>,
     session: Session,
     info: web::Path<(String,)>,
-    request: HttpRequest,
-) -> ActixResult<HttpResponse> {
+) -> ActixResult<web::Json<LogoutResponse>> {
     let subapp = info.into_inner().0;
-    let base_url = format!("/{}", subapp);
-    is_authorised(&session, &app_state.authz_enforcer, request)?;
-    // if user already logged in, we clear his session token
+    let mut redirect_url = format!("/{}", subapp);
+    // if user already logged in, we end his session
     let user_key = "user";
-    if (session.get::<String>(user_key)?).is_some() {
-        // session.remove(user_key);
-        session.clear();
+    if let Some(email) = session.get::<String>(user_key)? {
+        if let Some(session_id) = session.get::<String>(SESSION_ID_KEY)? {
+            let tokens = wrap_internal_server_error(app_state.token_store.get(&session_id))?;
+            // Drops the stored session and its tokens, and closes the session's SSE streams
+            wrap_internal_server_error(app_state.sessions.revoke(&email, &session_id))?;
+            if let Some(tokens) = tokens {
+                if let Some(end_session_url) =
+                    get_provider_logout_url(&app_state, &tokens, &subapp).await
+                {
+                    redirect_url = end_session_url.to_string();
+                }
+            }
+        }
+        session.purge();
     }
+    Ok(web::Json(LogoutResponse { redirect_url }))
+}
+
+/// Where providers send users back to after logging out, with the subapp as state
+#[get("/public/logged_out")]
+async fn logged_out(query: web::Query<PostLogoutRedirect>) -> ActixResult<HttpResponse> {
+    // Only ever redirect within this site
+    let location = match &query.state {
+        Some(subapp) if validate_slug(subapp).is_ok() => format!("/{}", subapp),
+        _ => "/".to_string(),
+    };
     Ok(HttpResponse::TemporaryRedirect()
-        .insert_header(("Location", base_url))
+        .insert_header(("Location", location))
         .body("Logged out. Redirecting"))
 }
 
-#[get("/public/subscribe")]
+/// OpenID Connect back-channel logout. The provider tells us a user logged out there, and their
+/// sessions from that login end here too
+#[post("/public/backchannel_logout/{provider}")]
+async fn backchannel_logout(
+    app_state: web::Data<AppState>,
+    info: web::Path<(String,)>,
+    form: web::Form<BackchannelLogout>,
+) -> ActixResult<HttpResponse> {
+    let provider = info.into_inner().0;
+    let provider_config = match app_state.oidc_config.provider(&provider) {
+        Some(provider_config) => provider_config,
+        None => return Err(ErrorNotFound(format!("No login provider {}", provider))),
+    };
+    let logout_token = wrap_logout_error(
+        verify_logout_token(&app_state.oidc_clients, provider_config, &form.logout_token).await,
+    )?;
+    wrap_internal_server_error(app_state.sessions.revoke_provider_sessions(
+        &provider,
+        logout_token.subject.as_deref(),
+        logout_token.sid.as_deref(),
+    ))?;
+    // The spec asks for the response not to be cached
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .finish())
+}
+
+#[get("/public/{subapp}/subscribe")]
 async fn subscribe(
     app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
     session: Session,
+    info: web::Path<(String,)>,
     request: HttpRequest,
-) -> impl Responder {
-    let user = match is_authorised(&session, &app_state.authz_enforcer, request) {
-        Ok(x) => x,
-        Err(e) => return Err(e),
-    };
-    info!("Subscriber added");
+) -> ActixResult<impl Responder> {
+    let subapp = info.into_inner().0;
+    // Browsers send the id of the last event they saw when they reconnect
+    let last_event_id = request
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let db_connection = &mut app_state.db_connection_pool.get().unwrap();
+    let queue = get_queue_or_not_found(db_connection, &subapp)?;
+    info!("Subscriber added to {}", queue.slug);
     let (sender, receiver) = sse::channel(10);
-    let mut sse_senders = app_state.sse_senders.lock().await;
-    let uuid = Uuid::new_v4().to_string();
-    let item = SseSender { sender, uuid };
-    if let Some(sender_list) = sse_senders.get(&user.email) {
-        let mut new_list = sender_list.clone();
-        new_list.push(item);
-        sse_senders.insert(user.email, new_list);
-    } else {
-        sse_senders.insert(user.email, vec![item]);
-    }
-    // (*sse_senders).push(sender);
+    // subscribe before the first snapshot is read so that no change is missed in between
+    let subscription = app_state.event_bus.subscribe(&queue.slug, last_event_id);
+    // The stream is closed if the user's session is revoked
+    let revocation = session
+        .get::<String>(SESSION_ID_KEY)?
+        .map(|session_id| app_state.sessions.watch(session_id));
+    actix_web::rt::spawn(forward_to_subscriber(
+        app_state.db_connection_pool.clone(),
+        queue.id,
+        user.into_inner().email,
+        subscription,
+        revocation,
+        sender,
+    ));
     Ok(receiver.with_retry_duration(Duration::from_secs(10)))
 }
 
-#[get("/public/get_selected_number")]
+#[get("/public/{subapp}/get_selected_number")]
 async fn get_selected_number(
     app_state: web::Data<AppState>,
-    session: Session,
-    request: HttpRequest,
+    user: AuthenticatedUser,
+    info: web::Path<(String,)>,
 ) -> ActixResult<web::Json<ServerSentData>> {
-    let user = is_authorised(&session, &app_state.authz_enforcer, request)?;
+    let subapp = info.into_inner().0;
     let db_connection = &mut app_state.db_connection_pool.get().unwrap();
-    let selected_number = wrap_internal_server_error(database::get_selected_queue(db_connection))?;
-    let assigned_number = wrap_internal_server_error(database::get_user_assigned_queue(
+    let queue = get_queue_or_not_found(db_connection, &subapp)?;
+    let server_sent_data = wrap_internal_server_error(database::get_server_sent_data(
         db_connection,
+        queue.id,
         &user.email,
     ))?;
-    let (abandoned_numbers, done_numbers) = wrap_internal_server_error(
-        database::get_abandoned_and_processed(db_connection, &user.email),
-    )?;
-    Ok(web::Json(ServerSentData {
-        selected_number,
-        assigned_number,
-        abandoned_numbers,
-        done_numbers,
-    }))
+    Ok(web::Json(server_sent_data))
     // match selected_number {
     //     Some(number) => Ok(number.to_string()),
     //     None => Ok("None".to_string()),
     // }
 }
 
+/// List all queues for the queue directory
+#[get("/public/queues")]
+async fn get_queues(app_state: web::Data<AppState>) -> ActixResult<web::Json<Vec<QueueSummary>>> {
+    let db_connection = &mut app_state.db_connection_pool.get().unwrap();
+    let queues = wrap_internal_server_error(database::get_queue_summaries(db_connection))?;
+    Ok(web::Json(queues))
+}
+
 /// API to add new queue
-#[post("/api/get_new_number")]
+#[post("/api/{subapp}/get_new_number")]
 async fn get_new_number(
     app_state: web::Data<AppState>,
-    session: Session,
-    request: HttpRequest,
+    user: AuthenticatedUser,
+    info: web::Path<(String,)>,
 ) -> ActixResult<web::Json<UserInfo>> {
-    let user = is_authorised(&session, &app_state.authz_enforcer, request)?;
+    let subapp = info.into_inner().0;
     let db_connection = &mut app_state.db_connection_pool.get().unwrap();
-    let user_info = wrap_internal_server_error(database::get_or_insert(db_connection, user))?;
+    let queue = get_queue_or_not_found(db_connection, &subapp)?;
+    let user_info = wrap_internal_server_error(database::get_or_insert(
+        db_connection,
+        queue.id,
+        user.into_inner(),
+    ))?;
+    app_state.event_bus.publish(&queue.slug, QueueEvent::Joined);
     Ok(web::Json(user_info))
 }
 
 /// API to get assigned number if it exists
-#[get("/api/get_assigned_number")]
+#[get("/api/{subapp}/get_assigned_number")]
 async fn get_assigned_number(
     app_state: web::Data<AppState>,
-    session: Session,
-    request: HttpRequest,
+    user: AuthenticatedUser,
+    info: web::Path<(String,)>,
 ) -> ActixResult<String> {
-    let user = is_authorised(&session, &app_state.authz_enforcer, request)?;
+    let subapp = info.into_inner().0;
     let db_connection = &mut app_state.db_connection_pool.get().unwrap();
+    let queue = get_queue_or_not_found(db_connection, &subapp)?;
     let _assigned_number = wrap_internal_server_error(database::get_user_assigned_queue(
         db_connection,
+        queue.id,
         &user.email,
     ))?;
     Ok("Ok".to_string())
 }
 
 /// API to abandon number
-#[post("/api/abandon_assigned_number")]
+#[post("/api/{subapp}/abandon_assigned_number")]
 async fn abandon_assigned_number(
     app_state: web::Data<AppState>,
-    session: Session,
-    request: HttpRequest,
+    user: AuthenticatedUser,
+    info: web::Path<(String,)>,
 ) -> ActixResult<String> {
-    let user = is_authorised(&session, &app_state.authz_enforcer, request)?;
+    let subapp = info.into_inner().0;
     let db_connection = &mut app_state.db_connection_pool.get().unwrap();
-    wrap_internal_server_error(database::set_to_abandoned(db_connection, user))?;
+    let queue = get_queue_or_not_found(db_connection, &subapp)?;
+    wrap_ticket_error(database::set_to_abandoned(
+        db_connection,
+        queue.id,
+        user.into_inner(),
+    ))?;
+    app_state
+        .event_bus
+        .publish(&queue.slug, QueueEvent::Abandoned);
     Ok("Ok".to_string())
 }
 
+/// API to create a new queue. The creator becomes the owner of the queue
+#[post("/api/queues")]
+async fn create_queue(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    body: web::Json<CreateQueueRequest>,
+) -> ActixResult<web::Json<QueueDetails>> {
+    let create_request = body.into_inner();
+    create_request.validate().map_err(ErrorBadRequest)?;
+    let db_connection = &mut app_state.db_connection_pool.get().unwrap();
+    if wrap_internal_server_error(database::get_queue(db_connection, &create_request.slug))?
+        .is_some()
+    {
+        return Err(ErrorConflict(format!(
+            "Slug {} is already taken",
+            create_request.slug
+        )));
+    }
+    let queue = wrap_internal_server_error(database::create_queue(
+        db_connection,
+        create_request,
+        &user.email,
+    ))?;
+    wrap_internal_server_error(
+        app_state
+            .authz_enforcer
+            .add_queue_member(&user.email, QueueRole::Owner, &queue.slug)
+            .await,
+    )?;
+    info!("{} created queue {}", user.email, queue.slug);
+    Ok(web::Json(QueueDetails {
+        slug: queue.slug,
+        name: queue.name,
+        description: queue.description,
+        owner: queue.owner,
+    }))
+}
+
 // Admin handlers
 #[get("/admin/test")]
-async fn admin_test(
+async fn admin_test() -> ActixResult<String> {
+    Ok("Admin Endpoint".to_string())
+}
+
+/// Admin API to list every user who logged in or was granted a role
+#[get("/admin/users")]
+async fn get_users(app_state: web::Data<AppState>) -> ActixResult<web::Json<Vec<UserRoles>>> {
+    let db_connection = &mut app_state.db_connection_pool.get().unwrap();
+    let users = wrap_internal_server_error(database::get_users_with_roles(db_connection))?;
+    Ok(web::Json(users))
+}
+
+/// Admin API to grant a role. Users do not need to have logged in before
+#[post("/admin/users/{email}/roles/{role}")]
+async fn grant_role(
+    app_state: web::Data<AppState>,
+    admin: AuthenticatedUser,
+    info: web::Path<(String, String)>,
+) -> ActixResult<web::Json<UserRoles>> {
+    let (email, role) = info.into_inner();
+    let role = Role::from_str(&role).map_err(ErrorBadRequest)?;
+    if !email.contains('@') {
+        return Err(ErrorBadRequest(format!("{} is not an email", email)));
+    }
+    wrap_internal_server_error(app_state.role_cache.grant(&email, role, &admin.email))?;
+    info!("{} granted {} to {}", admin.email, role, email);
+    get_user_roles(&app_state, &email)
+}
+
+/// Admin API to revoke a role. The last admin cannot be revoked
+#[delete("/admin/users/{email}/roles/{role}")]
+async fn revoke_role(
+    app_state: web::Data<AppState>,
+    admin: AuthenticatedUser,
+    info: web::Path<(String, String)>,
+) -> ActixResult<web::Json<UserRoles>> {
+    let (email, role) = info.into_inner();
+    let role = Role::from_str(&role).map_err(ErrorBadRequest)?;
+    let revoked = match app_state.role_cache.revoke(&email, role) {
+        Err(e) if e.downcast_ref::<LastAdmin>().is_some() => {
+            return Err(ErrorConflict(e.to_string()))
+        }
+        result => wrap_internal_server_error(result)?,
+    };
+    if !revoked {
+        return Err(ErrorNotFound(format!(
+            "{} does not have role {}",
+            email, role
+        )));
+    }
+    info!("{} revoked {} from {}", admin.email, role, email);
+    get_user_roles(&app_state, &email)
+}
+
+/// Admin API to log a user out of every session
+#[delete("/admin/users/{email}/sessions")]
+async fn revoke_user_sessions(
+    app_state: web::Data<AppState>,
+    admin: AuthenticatedUser,
+    info: web::Path<(String,)>,
+) -> ActixResult<web::Json<RevokedSessions>> {
+    let email = normalise_email(&info.into_inner().0);
+    let revoked = wrap_internal_server_error(app_state.sessions.revoke_all(&email))?;
+    info!("{} revoked {} sessions of {}", admin.email, revoked, email);
+    Ok(web::Json(RevokedSessions { revoked }))
+}
+
+/// Admin API to list the authorisation policies
+#[get("/admin/policies")]
+async fn get_policies(app_state: web::Data<AppState>) -> ActixResult<web::Json<Vec<Policy>>> {
+    let policies = wrap_internal_server_error(app_state.authz_enforcer.get_policies())?;
+    Ok(web::Json(policies))
+}
+
+/// Admin API to add a policy. It applies to requests straight away
+#[post("/admin/policies")]
+async fn add_policy(
+    app_state: web::Data<AppState>,
+    admin: AuthenticatedUser,
+    body: web::Json<PolicyRequest>,
+) -> ActixResult<web::Json<Policy>> {
+    let policy_request = body.into_inner();
+    policy_request.validate().map_err(ErrorBadRequest)?;
+    let policy = wrap_policy_error(app_state.authz_enforcer.add_policy(policy_request).await)?;
+    info!("{} added policy {:?}", admin.email, policy);
+    Ok(web::Json(policy))
+}
+
+/// Admin API to replace a policy
+#[put("/admin/policies/{id}")]
+async fn update_policy(
+    app_state: web::Data<AppState>,
+    admin: AuthenticatedUser,
+    info: web::Path<(i32,)>,
+    body: web::Json<PolicyRequest>,
+) -> ActixResult<web::Json<Policy>> {
+    let id = info.into_inner().0;
+    let policy_request = body.into_inner();
+    policy_request.validate().map_err(ErrorBadRequest)?;
+    match wrap_policy_error(
+        app_state
+            .authz_enforcer
+            .update_policy(id, policy_request)
+            .await,
+    )? {
+        Some(policy) => {
+            info!("{} updated policy {:?}", admin.email, policy);
+            Ok(web::Json(policy))
+        }
+        None => Err(ErrorNotFound(format!("No policy {}", id))),
+    }
+}
+
+/// Admin API to remove a policy. Returns the removed policy
+#[delete("/admin/policies/{id}")]
+async fn remove_policy(
+    app_state: web::Data<AppState>,
+    admin: AuthenticatedUser,
+    info: web::Path<(i32,)>,
+) -> ActixResult<web::Json<Policy>> {
+    let id = info.into_inner().0;
+    match wrap_policy_error(app_state.authz_enforcer.remove_policy(id).await)? {
+        Some(policy) => {
+            info!("{} removed policy {:?}", admin.email, policy);
+            Ok(web::Json(policy))
+        }
+        None => Err(ErrorNotFound(format!("No policy {}", id))),
+    }
+}
+
+/// Admin API to serve the next waiting number. Returns the newly selected number
+#[post("/admin/{subapp}/call_next")]
+async fn call_next(
+    app_state: web::Data<AppState>,
+    info: web::Path<(String,)>,
+) -> ActixResult<web::Json<Option<i32>>> {
+    let subapp = info.into_inner().0;
+    let db_connection = &mut app_state.db_connection_pool.get().unwrap();
+    let queue = get_queue_or_not_found(db_connection, &subapp)?;
+    let selected_number = wrap_ticket_error(database::call_next(db_connection, queue.id))?;
+    app_state.event_bus.publish(&queue.slug, QueueEvent::Called);
+    Ok(web::Json(selected_number))
+}
+
+/// Admin API to start serving the called number. Returns the number being served
+#[post("/admin/{subapp}/start_serving")]
+async fn start_serving(
+    app_state: web::Data<AppState>,
+    info: web::Path<(String,)>,
+) -> ActixResult<web::Json<Option<i32>>> {
+    let subapp = info.into_inner().0;
+    let db_connection = &mut app_state.db_connection_pool.get().unwrap();
+    let queue = get_queue_or_not_found(db_connection, &subapp)?;
+    let serving_number = wrap_ticket_error(database::transition_selected(
+        db_connection,
+        queue.id,
+        TicketStatus::Serving,
+    ))?;
+    app_state
+        .event_bus
+        .publish(&queue.slug, QueueEvent::Serving);
+    Ok(web::Json(serving_number))
+}
+
+/// Admin API to mark the selected number as served. Returns the number that was served
+#[post("/admin/{subapp}/mark_served")]
+async fn mark_served(
+    app_state: web::Data<AppState>,
+    info: web::Path<(String,)>,
+) -> ActixResult<web::Json<Option<i32>>> {
+    let subapp = info.into_inner().0;
+    let db_connection = &mut app_state.db_connection_pool.get().unwrap();
+    let queue = get_queue_or_not_found(db_connection, &subapp)?;
+    let served_number = wrap_ticket_error(database::transition_selected(
+        db_connection,
+        queue.id,
+        TicketStatus::Served,
+    ))?;
+    app_state.event_bus.publish(&queue.slug, QueueEvent::Served);
+    Ok(web::Json(served_number))
+}
+
+/// Admin API to mark the called number as a no-show. Returns the number that was marked
+#[post("/admin/{subapp}/mark_no_show")]
+async fn mark_no_show(
+    app_state: web::Data<AppState>,
+    info: web::Path<(String,)>,
+) -> ActixResult<web::Json<Option<i32>>> {
+    let subapp = info.into_inner().0;
+    let db_connection = &mut app_state.db_connection_pool.get().unwrap();
+    let queue = get_queue_or_not_found(db_connection, &subapp)?;
+    let no_show_number = wrap_ticket_error(database::transition_selected(
+        db_connection,
+        queue.id,
+        TicketStatus::NoShow,
+    ))?;
+    app_state.event_bus.publish(&queue.slug, QueueEvent::NoShow);
+    Ok(web::Json(no_show_number))
+}
+
+/// API to list the user's logged in sessions
+#[get("/api/sessions")]
+async fn get_sessions(
     app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
     session: Session,
+) -> ActixResult<web::Json<Vec<SessionSummary>>> {
+    let current_session_id = session.get::<String>(SESSION_ID_KEY)?;
+    let sessions = wrap_internal_server_error(
+        app_state
+            .sessions
+            .list(&user.email, current_session_id.as_deref()),
+    )?;
+    Ok(web::Json(sessions))
+}
+
+/// API to log the user out of one of their sessions, which may be this one
+#[delete("/api/sessions/{id}")]
+async fn revoke_session(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    session: Session,
+    info: web::Path<(String,)>,
+) -> ActixResult<web::Json<Vec<SessionSummary>>> {
+    let session_id = info.into_inner().0;
+    let revoked = wrap_internal_server_error(app_state.sessions.revoke(&user.email, &session_id))?;
+    if !revoked {
+        return Err(ErrorNotFound(format!("No session {}", session_id)));
+    }
+    let current_session_id = session.get::<String>(SESSION_ID_KEY)?;
+    if current_session_id.as_deref() == Some(session_id.as_str()) {
+        session.purge();
+    }
+    let sessions = wrap_internal_server_error(
+        app_state
+            .sessions
+            .list(&user.email, current_session_id.as_deref()),
+    )?;
+    Ok(web::Json(sessions))
+}
+
+/// API to log the user out everywhere, including this session
+#[delete("/api/sessions")]
+async fn revoke_sessions(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    session: Session,
+) -> ActixResult<web::Json<RevokedSessions>> {
+    let revoked = wrap_internal_server_error(app_state.sessions.revoke_all(&user.email))?;
+    session.purge();
+    Ok(web::Json(RevokedSessions { revoked }))
+}
+
+/// Owner API to list the queue's owners and staff
+#[get("/admin/{subapp}/staff")]
+async fn get_staff(
+    app_state: web::Data<AppState>,
+    info: web::Path<(String,)>,
+) -> ActixResult<web::Json<Vec<QueueMember>>> {
+    let subapp = info.into_inner().0;
+    let db_connection = &mut app_state.db_connection_pool.get().unwrap();
+    let queue = get_queue_or_not_found(db_connection, &subapp)?;
+    let members =
+        wrap_internal_server_error(app_state.authz_enforcer.get_queue_members(&queue.slug))?;
+    Ok(web::Json(members))
+}
+
+/// Owner API to let someone serve the queue. They do not need to have logged in before
+#[post("/admin/{subapp}/staff/{email}")]
+async fn add_staff(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    info: web::Path<(String, String)>,
+) -> ActixResult<web::Json<Vec<QueueMember>>> {
+    let (subapp, email) = info.into_inner();
+    if !email.contains('@') {
+        return Err(ErrorBadRequest(format!("{} is not an email", email)));
+    }
+    let db_connection = &mut app_state.db_connection_pool.get().unwrap();
+    let queue = get_queue_or_not_found(db_connection, &subapp)?;
+    wrap_internal_server_error(
+        app_state
+            .authz_enforcer
+            .add_queue_member(&email, QueueRole::Staff, &queue.slug)
+            .await,
+    )?;
+    info!(
+        "{} added {} to the staff of {}",
+        user.email, email, queue.slug
+    );
+    let members =
+        wrap_internal_server_error(app_state.authz_enforcer.get_queue_members(&queue.slug))?;
+    Ok(web::Json(members))
+}
+
+/// Owner API to stop someone serving the queue
+#[delete("/admin/{subapp}/staff/{email}")]
+async fn remove_staff(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+    info: web::Path<(String, String)>,
+) -> ActixResult<web::Json<Vec<QueueMember>>> {
+    let (subapp, email) = info.into_inner();
+    let db_connection = &mut app_state.db_connection_pool.get().unwrap();
+    let queue = get_queue_or_not_found(db_connection, &subapp)?;
+    let removed = wrap_internal_server_error(
+        app_state
+            .authz_enforcer
+            .remove_queue_member(&email, QueueRole::Staff, &queue.slug)
+            .await,
+    )?;
+    if !removed {
+        return Err(ErrorNotFound(format!(
+            "{} is not staff of {}",
+            email, queue.slug
+        )));
+    }
+    info!(
+        "{} removed {} from the staff of {}",
+        user.email, email, queue.slug
+    );
+    let members =
+        wrap_internal_server_error(app_state.authz_enforcer.get_queue_members(&queue.slug))?;
+    Ok(web::Json(members))
+}
+
+/// Forward the request to a configured downstream API on behalf of the logged in user.
+/// Each route is authorised by its own casbin policy on `/remote/{name}/*`
+#[route(
+    "/remote/{name}/{tail:.*}",
+    method = "GET",
+    method = "POST",
+    method = "PUT",
+    method = "PATCH",
+    method = "DELETE"
+)]
+async fn remote_proxy(
+    app_state: web::Data<AppState>,
+    session: Session,
+    info: web::Path<(String, String)>,
     request: HttpRequest,
-) -> ActixResult<String> {
-    is_authorised(&session, &app_state.authz_enforcer, request)?;
-    Ok("Admin Endpoint".to_string())
+    body: web::Bytes,
+) -> ActixResult<HttpResponse> {
+    let name = info.into_inner().0;
+    let route = match app_state.remote_routes.get(&name) {
+        Some(route) => route,
+        None => return Err(ErrorNotFound(format!("No remote route {}", name))),
+    };
+    if route.require_csrf && !has_csrf_header(request.headers()) {
+        return Err(MissingCsrfHeader.into());
+    }
+    let target_url = remote::target_url(route, &request).map_err(ErrorBadRequest)?;
+    let access_token = match wrap_oidc_error(
+        app_state
+            .token_store
+            .get_access_token(&app_state.oidc_config, &app_state.oidc_clients, &session)
+            .await,
+    )? {
+        Some(access_token) => access_token,
+        None => return Err(ErrorUnauthorized("Please log in again")),
+    };
+    match app_state
+        .remote_routes
+        .forward(route, target_url, &request, body, &access_token)
+        .await
+    {
+        Ok(response) => Ok(response),
+        Err(e) => {
+            error!("{:#}", e);
+            Err(ErrorBadGateway(e.to_string()))
+        }
+    }
 }
 
 // utils
+/// Remember where the login started and send the user off to the provider
+async fn start_login(
+    app_state: &AppState,
+    session: &Session,
+    subapp: String,
+    provider: String,
+) -> ActixResult<HttpResponse> {
+    let base_url = format!("/{}", subapp);
+    // if user already logged in, we skip this flow
+    if let Some(email) = session.get::<String>("user")? {
+        if !email.is_empty() {
+            info!("User already logged in");
+            return Ok(HttpResponse::TemporaryRedirect()
+                .insert_header(("Location", base_url))
+                .body("Already logged in. Redirecting"));
+        }
+    }
+
+    let anonuser = "anonuser";
+    let anonuserid = if let Some(anonuserid) = session.get::<String>(anonuser)? {
+        // info!("Anonymous user already has a session_id: {}", anonuserid);
+        anonuserid
+    } else {
+        info!("Anonymous user does NOT have a session_id. Generating one for him");
+        let uuid = Uuid::new_v4().to_string();
+        session.insert(anonuser, uuid.clone())?;
+        uuid
+    };
+    let provider_config = match app_state.oidc_config.provider(&provider) {
+        Some(provider_config) => provider_config,
+        None => return Err(ErrorNotFound(format!("No login provider {}", provider))),
+    };
+    let (url, csrf_token, nonce, pkce_verifier) =
+        wrap_oidc_error(get_oidc_login(&app_state.oidc_clients, provider_config).await)?;
+    let pending_login = PendingLogin::new(csrf_token, nonce, pkce_verifier, subapp, provider);
+    wrap_internal_server_error(app_state.pending_logins.insert(&anonuserid, pending_login))?;
+    Ok(HttpResponse::TemporaryRedirect()
+        .insert_header(("Location", url.to_string()))
+        .body("Redirecting to login"))
+}
+
+/// The user's roles straight from the database, bypassing the cache
+fn get_user_roles(app_state: &AppState, email: &str) -> ActixResult<web::Json<UserRoles>> {
+    let email = normalise_email(email);
+    let db_connection = &mut app_state.db_connection_pool.get().unwrap();
+    let roles = wrap_internal_server_error(database::get_user_roles(db_connection, &email))?;
+    Ok(web::Json(UserRoles { email, roles }))
+}
+
+/// Resolve the queue backing a subapp, or respond with 404 if there is none
+fn get_queue_or_not_found(
+    db_connection: &mut SqliteConnection,
+    subapp: &str,
+) -> ActixResult<QueueRecord> {
+    match wrap_internal_server_error(database::get_queue(db_connection, subapp))? {
+        Some(queue) => Ok(queue),
+        None => Err(ErrorNotFound(format!("No queue found for {}", subapp))),
+    }
+}
+
+/// Illegal ticket status moves are the caller's fault, so they are reported as a conflict
+fn wrap_ticket_error<T>(result: AnyhowResult<T>) -> ActixResult<T> {
+    match result {
+        Err(e) if e.downcast_ref::<IllegalTransition>().is_some() => {
+            Err(ErrorConflict(e.to_string()))
+        }
+        result => wrap_internal_server_error(result),
+    }
+}
+
+/// Rules that do not evaluate are the caller's fault, and so are changes that clash with the
+/// existing policies
+fn wrap_policy_error<T>(result: AnyhowResult<T>) -> ActixResult<T> {
+    match result {
+        Err(e) if e.downcast_ref::<InvalidPolicy>().is_some() => {
+            Err(ErrorBadRequest(e.to_string()))
+        }
+        Err(e) if e.downcast_ref::<PolicyConflict>().is_some() => Err(ErrorConflict(e.to_string())),
+        result => wrap_internal_server_error(result),
+    }
+}
+
+/// Where to log the user out at the provider as well. None if the provider has no end session
+/// endpoint or cannot be reached, in which case the user is only logged out here
+async fn get_provider_logout_url(
+    app_state: &AppState,
+    tokens: &UserTokenRow,
+    subapp: &str,
+) -> Option<Url> {
+    let provider_config = app_state.oidc_config.provider(&tokens.provider)?;
+    match get_end_session_url(
+        &app_state.oidc_clients,
+        provider_config,
+        tokens.id_token.as_deref(),
+        subapp,
+    )
+    .await
+    {
+        Ok(end_session_url) => end_session_url,
+        Err(e) => {
+            warn!(
+                "Logging out of provider {} skipped: {:#}",
+                tokens.provider, e
+            );
+            None
+        }
+    }
+}
+
+/// Logout tokens that do not verify are rejected as the provider's fault
+fn wrap_logout_error<T>(result: AnyhowResult<T>) -> ActixResult<T> {
+    match result {
+        Err(e) if e.downcast_ref::<InvalidLogoutToken>().is_some() => {
+            warn!("{}", e);
+            Err(ErrorBadRequest(e.to_string()))
+        }
+        result => wrap_oidc_error(result),
+    }
+}
+
+/// An unreachable identity provider is reported as 503 so the user knows to retry later
+fn wrap_oidc_error<T>(result: AnyhowResult<T>) -> ActixResult<T> {
+    match result {
+        Err(e) if e.downcast_ref::<ProviderUnavailable>().is_some() => {
+            error!("{:#}", e);
+            Err(ErrorServiceUnavailable(e.to_string()))
+        }
+        result => wrap_internal_server_error(result),
+    }
+}
+
 fn wrap_internal_server_error<T>(result: AnyhowResult<T>) -> ActixResult<T> {
     match result {
         Ok(v) => Ok(v),
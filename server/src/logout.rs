@@ -0,0 +1,399 @@
+use crate::config::OidcProviderConfig;
+use crate::oidc::OidcClients;
+use anyhow::Result as AnyhowResult;
+use chrono::prelude::*;
+use log::info;
+use openidconnect::{
+    core::{CoreJsonWebKeySet, CoreJsonWebKeyUse, CoreJwsSigningAlgorithm},
+    url::Url,
+    JsonWebKey, JsonWebKeyId, SignatureVerificationError,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+use std::fmt;
+
+/// Logout tokens must carry this event, so other kinds of JWT from the provider are refused
+const BACKCHANNEL_LOGOUT_EVENT: &str = "http://schemas.openid.net/event/backchannel-logout";
+/// Logout tokens issued longer ago than this are refused
+const MAX_LOGOUT_TOKEN_AGE_SECONDS: i64 = 300;
+/// How far the provider's clock may be ahead of ours
+const CLOCK_SKEW_SECONDS: i64 = 60;
+
+/// What the provider posts to the back-channel logout endpoint
+#[derive(Deserialize)]
+pub struct BackchannelLogout {
+    pub logout_token: String,
+}
+
+/// Query the provider sends users back to `/public/logged_out` with
+#[derive(Deserialize)]
+pub struct PostLogoutRedirect {
+    pub state: Option<String>,
+}
+
+/// The provider sent a logout token that cannot be trusted or does not follow the spec
+#[derive(Debug)]
+pub struct InvalidLogoutToken(String);
+
+impl fmt::Display for InvalidLogoutToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid logout token: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidLogoutToken {}
+
+/// Which logins the provider ended. At least one of the two is set
+pub struct LogoutToken {
+    /// The `sub` claim, all of the user's sessions when there is no `sid`
+    pub subject: Option<String>,
+    pub sid: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: CoreJwsSigningAlgorithm,
+    kid: Option<JsonWebKeyId>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audiences {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct LogoutTokenClaims {
+    iss: String,
+    aud: Audiences,
+    iat: i64,
+    exp: Option<i64>,
+    #[serde(default)]
+    events: serde_json::Map<String, Value>,
+    sub: Option<String>,
+    sid: Option<String>,
+    nonce: Option<Value>,
+}
+
+/// Where to send the user to log out at the provider too. None if the provider has no end
+/// session endpoint, in which case only the local session ends. The provider sends the user back
+/// to `/public/logged_out` with the subapp as state
+pub async fn get_end_session_url(
+    oidc_clients: &OidcClients,
+    provider_config: &OidcProviderConfig,
+    id_token: Option<&str>,
+    subapp: &str,
+) -> AnyhowResult<Option<Url>> {
+    let discovered = oidc_clients.get_discovered(provider_config).await?;
+    let mut end_session_url = match discovered.end_session_url {
+        Some(end_session_url) => end_session_url,
+        None => return Ok(None),
+    };
+    {
+        let mut query = end_session_url.query_pairs_mut();
+        if let Some(id_token) = id_token {
+            query.append_pair("id_token_hint", id_token);
+        }
+        query
+            .append_pair("client_id", provider_config.client_id.as_str())
+            .append_pair(
+                "post_logout_redirect_uri",
+                provider_config.post_logout_redirect_url.as_str(),
+            )
+            .append_pair("state", subapp);
+    }
+    Ok(Some(end_session_url))
+}
+
+/// Check the logout token's signature against the provider's keys, and its claims against the
+/// OpenID Connect Back-Channel Logout spec
+pub async fn verify_logout_token(
+    oidc_clients: &OidcClients,
+    provider_config: &OidcProviderConfig,
+    logout_token: &str,
+) -> AnyhowResult<LogoutToken> {
+    let parts = logout_token.split('.').collect::<Vec<&str>>();
+    let (header, payload, signature) = match parts[..] {
+        [header, payload, signature] => (header, payload, signature),
+        _ => return Err(invalid("not a signed JWT")),
+    };
+    let message = &logout_token[..header.len() + 1 + payload.len()];
+    let header = decode_json::<JwtHeader>(header).map_err(|_| invalid("malformed header"))?;
+    // Symmetric algorithms would trust anyone holding our client secret, and `none` anyone at all
+    if matches!(
+        header.alg,
+        CoreJwsSigningAlgorithm::None
+            | CoreJwsSigningAlgorithm::HmacSha256
+            | CoreJwsSigningAlgorithm::HmacSha384
+            | CoreJwsSigningAlgorithm::HmacSha512
+    ) {
+        return Err(invalid("unsupported signing algorithm"));
+    }
+    let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| invalid("malformed signature"))?;
+    let discovered = oidc_clients.get_discovered(provider_config).await?;
+    let verified = match verify_signature(&discovered.jwks, &header, message, &signature) {
+        // The provider probably rotated its signing keys, so fetch them again and retry once
+        Err(SignatureVerificationError::NoMatchingKey) => {
+            match oidc_clients.refresh_keys(provider_config).await? {
+                Some(discovered) => {
+                    info!(
+                        "Unknown key id in logout token, refreshed provider {}",
+                        provider_config.name
+                    );
+                    verify_signature(&discovered.jwks, &header, message, &signature)
+                }
+                None => Err(SignatureVerificationError::NoMatchingKey),
+            }
+        }
+        result => result,
+    };
+    verified.map_err(|_| invalid("signature does not verify"))?;
+
+    let claims =
+        decode_json::<LogoutTokenClaims>(payload).map_err(|_| invalid("malformed claims"))?;
+    if claims.iss != provider_config.issuer_url.as_str() {
+        return Err(invalid("issued by another provider"));
+    }
+    let audiences = match claims.aud {
+        Audiences::One(audience) => vec![audience],
+        Audiences::Many(audiences) => audiences,
+    };
+    if !audiences
+        .iter()
+        .any(|audience| audience == provider_config.client_id.as_str())
+    {
+        return Err(invalid("issued for another client"));
+    }
+    let now = Utc::now().timestamp();
+    if claims.iat > now + CLOCK_SKEW_SECONDS || claims.iat < now - MAX_LOGOUT_TOKEN_AGE_SECONDS {
+        return Err(invalid("issued too long ago or in the future"));
+    }
+    if matches!(claims.exp, Some(exp) if exp < now - CLOCK_SKEW_SECONDS) {
+        return Err(invalid("expired"));
+    }
+    if !claims.events.contains_key(BACKCHANNEL_LOGOUT_EVENT) {
+        return Err(invalid("not a back-channel logout event"));
+    }
+    // Keeps ID tokens from being passed off as logout tokens
+    if claims.nonce.is_some() {
+        return Err(invalid("must not have a nonce"));
+    }
+    if claims.sub.is_none() && claims.sid.is_none() {
+        return Err(invalid("names neither a subject nor a session"));
+    }
+    Ok(LogoutToken {
+        subject: claims.sub,
+        sid: claims.sid,
+    })
+}
+
+fn invalid(reason: &str) -> anyhow::Error {
+    InvalidLogoutToken(reason.to_string()).into()
+}
+
+fn decode_json<T: DeserializeOwned>(part: &str) -> AnyhowResult<T> {
+    let json = base64::decode_config(part, base64::URL_SAFE_NO_PAD)?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+/// Succeeds if any of the provider's signing keys with the token's key id made the signature
+fn verify_signature(
+    jwks: &CoreJsonWebKeySet,
+    header: &JwtHeader,
+    message: &str,
+    signature: &[u8],
+) -> Result<(), SignatureVerificationError> {
+    let keys = jwks
+        .keys()
+        .iter()
+        .filter(|key| header.kid.is_none() || key.key_id() == header.kid.as_ref())
+        .filter(|key| matches!(key.key_use(), None | Some(CoreJsonWebKeyUse::Signature)))
+        .collect::<Vec<_>>();
+    if keys.is_empty() {
+        return Err(SignatureVerificationError::NoMatchingKey);
+    }
+    if keys.iter().any(|key| {
+        key.verify_signature(&header.alg, message.as_bytes(), signature)
+            .is_ok()
+    }) {
+        Ok(())
+    } else {
+        Err(SignatureVerificationError::CryptoError(
+            "No key verifies the signature".to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::signing_key;
+    use openidconnect::PrivateSigningKey;
+    use serde_json::json;
+
+    const ISSUER: &str = "https://idp.example.com";
+    const CLIENT_ID: &str = "queue-app";
+    fn provider() -> OidcProviderConfig {
+        OidcProviderConfig::new(
+            "test",
+            "Test".to_string(),
+            ISSUER,
+            CLIENT_ID.to_string(),
+            "secret".to_string(),
+            "email",
+            "http://localhost:8080",
+        )
+        .unwrap()
+    }
+
+    /// Clients that know the provider, with `k1` as its only signing key
+    fn oidc_clients() -> OidcClients {
+        let oidc_clients = OidcClients::default();
+        let jwks: CoreJsonWebKeySet =
+            CoreJsonWebKeySet::new(vec![signing_key("k1").as_verification_key()]);
+        oidc_clients.insert_discovered(&provider(), jwks);
+        oidc_clients
+    }
+
+    fn valid_claims() -> Value {
+        json!({
+            "iss": ISSUER,
+            "aud": CLIENT_ID,
+            "iat": Utc::now().timestamp(),
+            "exp": Utc::now().timestamp() + 120,
+            "jti": "logout-1",
+            "sub": "user-1",
+            "sid": "session-1",
+            "events": { BACKCHANNEL_LOGOUT_EVENT: {} },
+        })
+    }
+
+    fn encode(value: &Value) -> String {
+        base64::encode_config(value.to_string(), base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Sign the claims with RS256 and the key `kid`
+    fn sign(claims: &Value, kid: &str) -> String {
+        let message = format!(
+            "{}.{}",
+            encode(&json!({ "alg": "RS256", "kid": kid })),
+            encode(claims)
+        );
+        let signature = signing_key(kid)
+            .sign(
+                &CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
+                message.as_bytes(),
+            )
+            .unwrap();
+        format!(
+            "{}.{}",
+            message,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    async fn verify(logout_token: &str) -> AnyhowResult<LogoutToken> {
+        verify_logout_token(&oidc_clients(), &provider(), logout_token).await
+    }
+
+    /// The reason the token was refused
+    async fn rejection(logout_token: &str) -> String {
+        match verify(logout_token).await {
+            Ok(_) => panic!("Logout token was accepted"),
+            Err(e) => {
+                e.downcast::<InvalidLogoutToken>()
+                    .expect("Expected an invalid logout token")
+                    .0
+            }
+        }
+    }
+
+    async fn rejection_of_claims(change: impl FnOnce(&mut Value)) -> String {
+        let mut claims = valid_claims();
+        change(&mut claims);
+        rejection(&sign(&claims, "k1")).await
+    }
+
+    #[actix_web::test]
+    async fn accepts_a_valid_token() {
+        let logout = verify(&sign(&valid_claims(), "k1")).await.unwrap();
+        assert_eq!(logout.subject.as_deref(), Some("user-1"));
+        assert_eq!(logout.sid.as_deref(), Some("session-1"));
+    }
+
+    #[actix_web::test]
+    async fn accepts_an_audience_list_and_a_token_without_expiry() {
+        let mut claims = valid_claims();
+        claims["aud"] = json!(["other-app", CLIENT_ID]);
+        claims.as_object_mut().unwrap().remove("exp");
+        assert!(verify(&sign(&claims, "k1")).await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn rejects_unsigned_and_symmetric_algorithms() {
+        let claims = encode(&valid_claims());
+        for alg in ["none", "HS256", "HS384", "HS512"] {
+            let header = encode(&json!({ "alg": alg, "kid": "k1" }));
+            let token = format!("{}.{}.{}", header, claims, encode(&json!("signature")));
+            assert_eq!(rejection(&token).await, "unsupported signing algorithm");
+        }
+    }
+
+    #[actix_web::test]
+    async fn rejects_a_bad_signature() {
+        let token = sign(&valid_claims(), "k1");
+        let (message, _) = token.rsplit_once('.').unwrap();
+        let other = sign(&json!({ "other": true }), "k1");
+        let (_, signature) = other.rsplit_once('.').unwrap();
+        let forged = format!("{}.{}", message, signature);
+        assert_eq!(rejection(&forged).await, "signature does not verify");
+        // Keys are refetched at most once a minute, so an unknown key is not looked up again
+        assert_eq!(
+            rejection(&sign(&valid_claims(), "k2")).await,
+            "signature does not verify"
+        );
+    }
+
+    #[actix_web::test]
+    async fn rejects_another_issuer_or_audience() {
+        let reason =
+            rejection_of_claims(|claims| claims["iss"] = json!("https://evil.example.com"));
+        assert_eq!(reason.await, "issued by another provider");
+        let reason = rejection_of_claims(|claims| claims["aud"] = json!("other-app"));
+        assert_eq!(reason.await, "issued for another client");
+        let reason = rejection_of_claims(|claims| claims["aud"] = json!(["other-app"]));
+        assert_eq!(reason.await, "issued for another client");
+    }
+
+    #[actix_web::test]
+    async fn rejects_stale_future_and_expired_tokens() {
+        let now = Utc::now().timestamp();
+        let stale = now - MAX_LOGOUT_TOKEN_AGE_SECONDS - 10;
+        let reason = rejection_of_claims(|claims| claims["iat"] = json!(stale));
+        assert_eq!(reason.await, "issued too long ago or in the future");
+        let future = now + CLOCK_SKEW_SECONDS + 10;
+        let reason = rejection_of_claims(|claims| claims["iat"] = json!(future));
+        assert_eq!(reason.await, "issued too long ago or in the future");
+        let expired = now - CLOCK_SKEW_SECONDS - 10;
+        let reason = rejection_of_claims(|claims| claims["exp"] = json!(expired));
+        assert_eq!(reason.await, "expired");
+    }
+
+    #[actix_web::test]
+    async fn rejects_tokens_that_are_not_logout_tokens() {
+        let reason = rejection_of_claims(|claims| {
+            claims["events"] = json!({ "http://example.com/other-event": {} })
+        });
+        assert_eq!(reason.await, "not a back-channel logout event");
+        let reason = rejection_of_claims(|claims| claims["nonce"] = json!("n-0S6_WzA2Mj"));
+        assert_eq!(reason.await, "must not have a nonce");
+        let reason = rejection_of_claims(|claims| {
+            let claims = claims.as_object_mut().unwrap();
+            claims.remove("sub");
+            claims.remove("sid");
+        });
+        assert_eq!(reason.await, "names neither a subject nor a session");
+    }
+}
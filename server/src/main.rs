@@ -1,28 +1,43 @@
 use actix_files as fs;
-use actix_session::storage::CookieSessionStore;
 use actix_session::SessionMiddleware;
 use actix_web::{cookie::Key, web::to, web::Data, App, HttpServer};
-use actix_web_lab::sse;
-use anyhow::Result;
-use common::ServerSentData;
-use database::{get_abandoned_and_processed, get_selected_queue, get_user_assigned_queue};
+use actix_web_lab::middleware::from_fn;
+use anyhow::{anyhow, Context, Result};
+use authz::PolicyEnforcer;
+use config::{OidcConfig, RemoteRouteConfig};
 use diesel::{
     r2d2::{ConnectionManager, Pool},
     SqliteConnection,
 };
-use futures::{executor::block_on, future::join_all};
+use events::EventBus;
 use log::info;
-use openidconnect::{CsrfToken, Nonce};
-use std::{collections::HashMap, sync::Arc, thread, time::Duration};
-use tokio::sync::Mutex;
+use oidc::OidcClients;
+use pending_logins::{MemoryPendingLoginStore, PendingLoginStore, SqlitePendingLoginStore};
+use remote::RemoteRoutes;
+use roles::RoleCache;
+use sessions::{SessionRegistry, SqliteSessionStore};
+use std::time::Duration;
+use tokens::TokenStore;
 mod auth;
+mod authz;
+mod config;
 pub mod database;
+mod events;
+mod logout;
+mod oidc;
+mod pending_logins;
+mod remote;
+mod roles;
 pub mod schema;
-use casbin::prelude::*;
+mod sessions;
+mod tokens;
 use std::env;
 
 use crate::database::establish_connection_pool;
 mod handlers;
+mod middleware;
+#[cfg(test)]
+mod test_support;
 
 #[actix_web::main]
 async fn main() -> Result<()> {
@@ -30,59 +45,59 @@ async fn main() -> Result<()> {
 
     info!("Establishing database connection");
     let db_connection_pool = establish_connection_pool();
-    let db_connection_pool_2 = establish_connection_pool();
-
-    // sse sender in another tokio task
-    let sender_list = Arc::new(Mutex::new(HashMap::new()));
-    let sender_list_copy = sender_list.clone();
-    let _sender_task = thread::spawn(move || {
-        info!("Starting server side event sender in background thread..");
-        block_on(sse_sender(sender_list_copy, db_connection_pool));
-    });
 
     // webserver
-    start_webserver(sender_list, db_connection_pool_2)
-        .await?
-        .await?;
+    start_webserver(db_connection_pool).await?.await?;
     Ok(())
 }
 
-pub struct OidcMetadata {
-    pub csrf_token: CsrfToken,
-    pub nonce: Nonce,
-    pub subapp: String,
-}
-
-#[derive(Clone)]
-pub struct SseSender {
-    uuid: String,
-    sender: sse::Sender,
-}
-
-/// Store a mutex of hashmap to persist csrftoken and nonce
 pub struct AppState {
-    pub session_oidc_state: Mutex<HashMap<String, OidcMetadata>>,
-    pub client_id: String,
-    pub client_secret: String,
-    pub sse_senders: Arc<Mutex<HashMap<String, Vec<SseSender>>>>,
-    pub authz_enforcer: Enforcer,
+    /// Persists csrf token, nonce and PKCE verifier while the user is at the identity provider
+    pub pending_logins: Box<dyn PendingLoginStore>,
+    pub oidc_config: OidcConfig,
+    pub oidc_clients: OidcClients,
+    pub token_store: TokenStore,
+    pub role_cache: RoleCache,
+    pub sessions: SessionRegistry,
+    pub remote_routes: RemoteRoutes,
+    pub event_bus: EventBus,
+    pub authz_enforcer: PolicyEnforcer,
     pub db_connection_pool: Pool<ConnectionManager<SqliteConnection>>, // pub db_connection: Arc<SqliteConnection>,
 }
 
-const GOOGLE_CLIENT_ID_KEY: &str = "GOOGLE_CLIENT_ID";
-const GOOGLE_CLIENT_SECRET_KEY: &str = "GOOGLE_CLIENT_SECRET";
 const SERVER_SECRET_KEY: &str = "SERVER_SECRET_KEY";
+const PENDING_LOGIN_STORE_KEY: &str = "PENDING_LOGIN_STORE";
+const PENDING_LOGIN_TTL_SECONDS_KEY: &str = "PENDING_LOGIN_TTL_SECONDS";
+/// Abandoned logins are swept this often
+const PENDING_LOGIN_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// Bounds the in-memory store, the oldest pending login is dropped beyond this
+const MAX_PENDING_LOGINS: usize = 10_000;
+/// Expired sessions are swept this often
+const SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(600);
+/// Comma separated emails that are made admins at startup
+const BOOTSTRAP_ADMINS_KEY: &str = "BOOTSTRAP_ADMINS";
+/// Role changes made on another instance show up after at most this long
+const ROLE_CACHE_TTL: Duration = Duration::from_secs(30);
 const DATABASE_URL_KEY: &str = "DATABASE_URL";
 const ANONYMOUS: &str = "anonymous";
 
 pub async fn start_webserver(
-    sse_senders: Arc<Mutex<HashMap<String, Vec<SseSender>>>>,
     db_connection_pool: Pool<ConnectionManager<SqliteConnection>>,
 ) -> Result<actix_web::dev::Server> {
-    let client_id =
-        env::var(GOOGLE_CLIENT_ID_KEY).expect("Missing the GOOGLE_CLIENT_ID environment variable.");
-    let client_secret = env::var(GOOGLE_CLIENT_SECRET_KEY)
-        .expect("Missing the GOOGLE_CLIENT_SECRET environment variable.");
+    let oidc_config = OidcConfig::from_env().context("Invalid OIDC configuration")?;
+    for provider in &oidc_config.providers {
+        info!(
+            "Provider {} logs in against {} with redirect {}",
+            provider.name,
+            provider.issuer_url.as_str(),
+            provider.redirect_url.as_str()
+        );
+    }
+    let remote_routes =
+        RemoteRouteConfig::all_from_env().context("Invalid remote route configuration")?;
+    for route in &remote_routes {
+        info!("Remote route {} forwards to {}", route.name, route.url);
+    }
     let secret_key = env::var(SERVER_SECRET_KEY).expect("Expected SERVER_SECRET_KEY to be present");
     assert!(
         secret_key.len() > 64,
@@ -92,24 +107,95 @@ pub async fn start_webserver(
     );
     let secret = Key::from(secret_key.as_bytes());
 
-    // Casbin for authZ stuff. Create an enforcer
-    let authz_enforcer = Enforcer::new("authz/abac_model.conf", "authz/abac_policy.csv").await?;
+    // Casbin for authZ stuff. Policies live in the database and can be edited at runtime
+    let authz_enforcer = PolicyEnforcer::new(db_connection_pool.clone()).await?;
 
     info!("Starting webserver in main thread");
 
+    let pending_login_ttl = env::var(PENDING_LOGIN_TTL_SECONDS_KEY)
+        .unwrap_or_else(|_| "600".to_string())
+        .parse::<u64>()
+        .with_context(|| format!("Expected {} to be a number", PENDING_LOGIN_TTL_SECONDS_KEY))?;
+    let pending_login_ttl = Duration::from_secs(pending_login_ttl);
+    let pending_logins: Box<dyn PendingLoginStore> =
+        match env::var(PENDING_LOGIN_STORE_KEY).as_deref() {
+            Ok("memory") => Box::new(MemoryPendingLoginStore::new(
+                pending_login_ttl,
+                MAX_PENDING_LOGINS,
+            )?),
+            Ok("sqlite") | Err(_) => Box::new(SqlitePendingLoginStore::new(
+                db_connection_pool.clone(),
+                pending_login_ttl,
+            )?),
+            Ok(other) => {
+                return Err(anyhow!(
+                    "Expected {} to be memory or sqlite, got {}",
+                    PENDING_LOGIN_STORE_KEY,
+                    other
+                ))
+            }
+        };
+
+    let role_cache = RoleCache::new(db_connection_pool.clone(), ROLE_CACHE_TTL);
+    let bootstrap_admins: Vec<String> = env::var(BOOTSTRAP_ADMINS_KEY)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|email| !email.is_empty())
+        .map(str::to_string)
+        .collect();
+    role_cache
+        .bootstrap_admins(&bootstrap_admins)
+        .context("Failed to bootstrap admins")?;
+
+    let session_store = SqliteSessionStore::new(db_connection_pool.clone());
     let app_state = Data::new(AppState {
-        session_oidc_state: Mutex::new(HashMap::<String, OidcMetadata>::new()),
-        client_id,
-        client_secret,
-        sse_senders,
+        pending_logins,
+        oidc_config,
+        oidc_clients: OidcClients::default(),
+        token_store: TokenStore::new(db_connection_pool.clone()),
+        role_cache,
+        sessions: SessionRegistry::new(db_connection_pool.clone()),
+        remote_routes: RemoteRoutes::new(remote_routes)?,
+        event_bus: EventBus::default(),
         authz_enforcer,
         db_connection_pool,
     });
 
+    // Discover the login providers once up front, then keep their metadata and keys fresh
+    app_state
+        .oidc_clients
+        .discover_all(&app_state.oidc_config)
+        .await;
+    let sweep_state = app_state.clone();
+    actix_web::rt::spawn(async move {
+        pending_logins::remove_expired_periodically(
+            sweep_state.pending_logins.as_ref(),
+            PENDING_LOGIN_SWEEP_INTERVAL,
+        )
+        .await
+    });
+    let sweep_store = session_store.clone();
+    actix_web::rt::spawn(async move {
+        sessions::remove_expired_periodically(&sweep_store, SESSION_SWEEP_INTERVAL).await
+    });
+    let refresh_state = app_state.clone();
+    actix_web::rt::spawn(async move {
+        refresh_state
+            .oidc_clients
+            .refresh_periodically(
+                &refresh_state.oidc_config,
+                refresh_state.oidc_config.discovery_ttl,
+            )
+            .await
+    });
+
     let server = HttpServer::new(move || {
         App::new()
+            .wrap(from_fn(middleware::authorise))
+            .wrap(from_fn(middleware::require_csrf_header))
             .wrap(
-                SessionMiddleware::builder(CookieSessionStore::default(), secret.clone())
+                SessionMiddleware::builder(session_store.clone(), secret.clone())
                     .cookie_secure(true)
                     .cookie_http_only(true)
                     .cookie_same_site(actix_web::cookie::SameSite::Lax)
@@ -121,14 +207,39 @@ pub async fn start_webserver(
             .app_data(app_state.clone())
             .service(handlers::hello)
             .service(handlers::login)
+            .service(handlers::login_with_provider)
+            .service(handlers::get_login_providers)
             .service(handlers::token_exchange)
             .service(handlers::get_user_info2)
             .service(handlers::logout)
+            .service(handlers::logged_out)
+            .service(handlers::backchannel_logout)
             .service(handlers::subscribe)
             .service(handlers::admin_test)
+            .service(handlers::get_users)
+            .service(handlers::grant_role)
+            .service(handlers::revoke_role)
+            .service(handlers::revoke_user_sessions)
+            .service(handlers::get_policies)
+            .service(handlers::add_policy)
+            .service(handlers::update_policy)
+            .service(handlers::remove_policy)
+            .service(handlers::call_next)
+            .service(handlers::start_serving)
+            .service(handlers::mark_served)
+            .service(handlers::mark_no_show)
+            .service(handlers::get_staff)
+            .service(handlers::add_staff)
+            .service(handlers::remove_staff)
+            .service(handlers::get_sessions)
+            .service(handlers::revoke_session)
+            .service(handlers::revoke_sessions)
             .service(handlers::get_new_number)
             .service(handlers::abandon_assigned_number)
+            .service(handlers::create_queue)
+            .service(handlers::get_queues)
             .service(handlers::get_selected_number)
+            .service(handlers::remote_proxy)
             .service(fs::Files::new("/", "./dist").index_file("index.html"))
             .default_service(to(handlers::spa_index))
         // .default_service(resource("").route(get().to(handlers::spa_index)))
@@ -136,90 +247,3 @@ pub async fn start_webserver(
 
     Ok(server.bind(("localhost", 8080)).unwrap().run())
 }
-
-/// Sender for current queue
-async fn sse_sender(
-    sse_senders: Arc<Mutex<HashMap<String, Vec<SseSender>>>>,
-    db_connection_pool: Pool<ConnectionManager<SqliteConnection>>,
-) {
-    // let now = SystemTime::now();
-    let mut current_number = None::<i32>;
-    // let mut current_senders = vec![];
-    loop {
-        thread::sleep(Duration::from_secs(1));
-        let db_connection = &mut db_connection_pool.get().unwrap();
-        let queried_number = get_selected_queue(db_connection).unwrap();
-        // if somebody new has joined the subscribers
-        // info!("{:?}, {:?}", queried_number, current_number);
-        // we should send him the value
-        if queried_number != current_number {
-            // use an Arc and Mutex. But does this mean I'm blocking new subscribers when I'm sending events
-            let mut senders = sse_senders.lock().await;
-            let futures = senders.clone().into_iter().map(|(user, senders)| {
-                let assigned_number = get_user_assigned_queue(db_connection, &user).unwrap();
-                let (abandoned_numbers, done_numbers) =
-                    get_abandoned_and_processed(db_connection, &user).unwrap();
-                senders.into_iter().map(move |sender| {
-                    // get assigned_number from sender.user
-                    send_message(
-                        ServerSentData {
-                            selected_number: queried_number,
-                            assigned_number,
-                            abandoned_numbers: abandoned_numbers.clone(),
-                            done_numbers: done_numbers.clone(),
-                        },
-                        user.clone(),
-                        sender,
-                    )
-                })
-            });
-            // channels that are able to have stuff sent to them are still alive
-            // we overwrite the original list of senders with list of new senders
-            let updated_senders = join_all(futures.flatten())
-                .await
-                .into_iter()
-                // .filter(|(user, after_sent)| after_sent.is_some())
-                .fold(
-                    HashMap::<String, Vec<SseSender>>::new(),
-                    |acc, (user, optional_sender)| {
-                        let mut result = acc;
-                        if let Some(sender) = optional_sender {
-                            if let Some(current_sender_list) = result.get(&user) {
-                                let mut new_list = current_sender_list.clone();
-                                new_list.push(sender);
-                                result.insert(user, new_list);
-                                result
-                            } else {
-                                result.insert(user, vec![sender]);
-                                result
-                            }
-                        } else {
-                            result
-                        }
-                    },
-                );
-            // let open_channels: Vec<sse::Sender> =
-            //     join_all(futures).await.into_iter().flatten().collect();
-            (*senders) = updated_senders;
-            current_number = queried_number;
-            // current_senders = open_channels;
-        }
-    }
-}
-
-async fn send_message(
-    message: ServerSentData,
-    user: String,
-    // sender: sse::Sender,
-    sender: SseSender,
-) -> (String, Option<SseSender>) {
-    let mut data = sse::Data::new(
-        serde_json::to_string(&message).expect("Expected to be able to serialise as string"),
-    );
-    data.set_event("data");
-    let after_sent = match sender.sender.send(data).await {
-        Ok(_) => Some(sender),
-        Err(_) => None,
-    };
-    (user, after_sent)
-}
@@ -0,0 +1,283 @@
+use crate::{auth::is_authorised, AppState};
+use actix_session::SessionExt;
+use actix_web::{
+    body::MessageBody,
+    dev::{ResourceDef, ServiceRequest, ServiceResponse},
+    error::ErrorInternalServerError,
+    http::{header::HeaderMap, Method, StatusCode},
+    web::Data,
+    Error, HttpMessage, HttpResponse, ResponseError,
+};
+use actix_web_lab::middleware::Next;
+use common::{ApiError, ApiErrorCode, CSRF_HEADER, CSRF_HEADER_VALUE};
+use std::fmt;
+
+/// Paths whose state-changing requests must come from our own frontend
+const CSRF_PROTECTED_PREFIXES: [&str; 2] = ["/api/", "/admin/"];
+
+/// Top level segments of the routes. Requests under them are always checked, even if no route
+/// matches and the frontend would answer
+const ROUTE_PREFIXES: [&str; 4] = ["api", "admin", "public", "remote"];
+
+/// Path parameter naming the queue a route acts on. Its value is the casbin domain
+const DOMAIN_PARAM: &str = "subapp";
+
+/// A state-changing request arrived without the anti-forgery header
+#[derive(Debug)]
+pub struct MissingCsrfHeader;
+
+impl fmt::Display for MissingCsrfHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Missing {}: {} header", CSRF_HEADER, CSRF_HEADER_VALUE)
+    }
+}
+
+impl ResponseError for MissingCsrfHeader {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::FORBIDDEN
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ApiError {
+            code: ApiErrorCode::MissingCsrfHeader,
+            message: self.to_string(),
+        })
+    }
+}
+
+pub fn has_csrf_header(headers: &HeaderMap) -> bool {
+    headers
+        .get(CSRF_HEADER)
+        .is_some_and(|value| value == CSRF_HEADER_VALUE)
+}
+
+/// Reject non-GET `/api/*` and `/admin/*` requests without the anti-forgery header.
+/// SameSite=Lax alone still lets other subdomains and old browsers forge requests
+pub async fn require_csrf_header(
+    request: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let is_safe_method = matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    );
+    // The router matches the decoded path, so `/%61pi/` must count as `/api/`
+    let path = request.match_info().as_str();
+    let is_protected = CSRF_PROTECTED_PREFIXES
+        .iter()
+        .any(|prefix| path.starts_with(prefix));
+    if !is_safe_method && is_protected && !has_csrf_header(request.headers()) {
+        return Err(MissingCsrfHeader.into());
+    }
+    next.call(request).await
+}
+
+/// Run the casbin check before every route and hand the user to the handler through
+/// `AuthenticatedUser`. Routes without a policy are denied. Only the static frontend, mounted
+/// at the root, is served without a check
+pub async fn authorise(
+    request: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    // Resolve the route the way the router will, from the decoded path. `request.path()` is raw,
+    // so `/%61dmin/users` would match no route here yet still reach `/admin/users`
+    let path = request.match_info().as_str().to_string();
+    let pattern = request
+        .request()
+        .resource_map()
+        .match_pattern(&path)
+        .unwrap_or_default();
+    // The frontend's files are registered with an empty pattern, and the SPA fallback with none
+    if !pattern.is_empty() || is_under_route_prefix(&path) {
+        let domain = get_domain(&request, &pattern);
+        let app_state = request
+            .app_data::<Data<AppState>>()
+            .cloned()
+            .ok_or_else(|| ErrorInternalServerError("Missing app state"))?;
+        let session = request.get_session();
+        let user_info = is_authorised(&session, &app_state, &domain, &path, request.method())?;
+        request.extensions_mut().insert(user_info);
+    }
+    next.call(request).await
+}
+
+fn is_under_route_prefix(path: &str) -> bool {
+    path.trim_start_matches('/')
+        .split('/')
+        .next()
+        .is_some_and(|segment| ROUTE_PREFIXES.contains(&segment))
+}
+
+/// The queue named by the route's `{subapp}` parameter, or empty if the route has none
+fn get_domain(request: &ServiceRequest, pattern: &str) -> String {
+    // Routing has not happened yet, so capture the parameters on a copy of the path
+    let mut path = request.match_info().clone();
+    if !ResourceDef::new(pattern).capture_match_info(&mut path) {
+        return String::new();
+    }
+    path.get(DOMAIN_PARAM).unwrap_or_default().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{database, test_support};
+    use actix_web::{
+        dev::Service,
+        test::{init_service, TestRequest},
+        web, App,
+    };
+    use actix_web_lab::middleware::from_fn;
+    use common::{QueueRole, Role};
+
+    /// Header the tests log in with, standing in for the session cookie
+    const TEST_USER_HEADER: &str = "x-test-user";
+
+    async fn log_in_from_header(
+        request: ServiceRequest,
+        next: Next<impl MessageBody>,
+    ) -> Result<ServiceResponse<impl MessageBody>, Error> {
+        if let Some(email) = request.headers().get(TEST_USER_HEADER) {
+            let email = email.to_str().unwrap().to_string();
+            request.get_session().insert("user", email).unwrap();
+        }
+        next.call(request).await
+    }
+
+    async fn ok() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    /// Stand-ins for the real handlers, registered with the same patterns in the same order
+    fn routes(cfg: &mut web::ServiceConfig) {
+        cfg.route("/admin/users", web::get().to(ok))
+            .route("/admin/policies/{id}", web::put().to(ok))
+            .route("/admin/{subapp}/call_next", web::post().to(ok))
+            .route("/api/{subapp}/get_new_number", web::post().to(ok))
+            .default_service(web::to(ok));
+    }
+
+    /// Rejections come back as errors rather than responses
+    fn to_status<B>(result: Result<ServiceResponse<B>, Error>) -> StatusCode {
+        match result {
+            Ok(response) => response.status(),
+            Err(e) => e.as_response_error().status_code(),
+        }
+    }
+
+    async fn status(request: TestRequest) -> StatusCode {
+        let app_state = test_support::app_state().await;
+        {
+            let db_connection = &mut app_state.db_connection_pool.get().unwrap();
+            database::grant_role(db_connection, "admin@example.com", Role::Admin, "test").unwrap();
+        }
+        let app = init_service(
+            App::new()
+                .wrap(from_fn(authorise))
+                .wrap(from_fn(log_in_from_header))
+                .app_data(app_state)
+                .configure(routes),
+        )
+        .await;
+        to_status(app.call(request.to_request()).await)
+    }
+
+    #[actix_web::test]
+    async fn encoded_admin_path_is_authorised() {
+        for uri in ["/admin/users", "/%61dmin/users", "/%61%64%6d%69%6e/users"] {
+            let request = TestRequest::get().uri(uri);
+            assert_eq!(status(request).await, StatusCode::FORBIDDEN, "{}", uri);
+            let request = TestRequest::get()
+                .uri(uri)
+                .insert_header((TEST_USER_HEADER, "admin@example.com"));
+            assert_eq!(status(request).await, StatusCode::OK, "{}", uri);
+        }
+        let request = TestRequest::post().uri("/%61dmin/demo/call_next");
+        assert_eq!(status(request).await, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn unmatched_paths_under_route_prefixes_are_authorised() {
+        let request = TestRequest::get().uri("/%61dmin/no_such_route");
+        assert_eq!(status(request).await, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn queue_routes_are_authorised_in_the_queue_domain() {
+        let app_state = test_support::app_state().await;
+        app_state
+            .authz_enforcer
+            .add_queue_member("staff@example.com", QueueRole::Staff, "demo")
+            .await
+            .unwrap();
+        let app = init_service(
+            App::new()
+                .wrap(from_fn(authorise))
+                .wrap(from_fn(log_in_from_header))
+                .app_data(app_state)
+                .configure(routes),
+        )
+        .await;
+        for (uri, expected) in [
+            ("/admin/demo/call_next", StatusCode::OK),
+            ("/admin/%64emo/call_next", StatusCode::OK),
+            ("/admin/other/call_next", StatusCode::FORBIDDEN),
+        ] {
+            let request = TestRequest::post()
+                .uri(uri)
+                .insert_header((TEST_USER_HEADER, "staff@example.com"));
+            assert_eq!(
+                to_status(app.call(request.to_request()).await),
+                expected,
+                "{}",
+                uri
+            );
+        }
+    }
+
+    async fn csrf_status(request: TestRequest) -> StatusCode {
+        let app = init_service(
+            App::new()
+                .wrap(from_fn(require_csrf_header))
+                .configure(routes),
+        )
+        .await;
+        to_status(app.call(request.to_request()).await)
+    }
+
+    #[actix_web::test]
+    async fn writes_need_the_csrf_header() {
+        let request = TestRequest::post().uri("/api/demo/get_new_number");
+        assert_eq!(csrf_status(request).await, StatusCode::FORBIDDEN);
+        let request = TestRequest::post()
+            .uri("/api/demo/get_new_number")
+            .insert_header((CSRF_HEADER, "0"));
+        assert_eq!(csrf_status(request).await, StatusCode::FORBIDDEN);
+        let request = TestRequest::post()
+            .uri("/api/demo/get_new_number")
+            .insert_header((CSRF_HEADER, CSRF_HEADER_VALUE));
+        assert_eq!(csrf_status(request).await, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn reads_pass_without_the_csrf_header() {
+        let request = TestRequest::get().uri("/admin/users");
+        assert_eq!(csrf_status(request).await, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn encoded_prefix_needs_the_csrf_header() {
+        for uri in ["/%61pi/demo/get_new_number", "/%61dmin/demo/call_next"] {
+            let request = TestRequest::post().uri(uri);
+            assert_eq!(csrf_status(request).await, StatusCode::FORBIDDEN, "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn frontend_is_served_without_a_check() {
+        for uri in ["/", "/demo", "/index.html"] {
+            let request = TestRequest::get().uri(uri);
+            assert_eq!(status(request).await, StatusCode::OK, "{}", uri);
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use crate::config::{OidcConfig, OidcProviderConfig};
+use anyhow::Result as AnyhowResult;
+use log::{info, warn};
+use openidconnect::{
+    core::{
+        CoreAuthDisplay, CoreClaimName, CoreClaimType, CoreClient, CoreClientAuthMethod,
+        CoreGrantType, CoreJsonWebKey, CoreJsonWebKeySet, CoreJsonWebKeyType, CoreJsonWebKeyUse,
+        CoreJweContentEncryptionAlgorithm, CoreJweKeyManagementAlgorithm, CoreJwsSigningAlgorithm,
+        CoreResponseMode, CoreResponseType, CoreSubjectIdentifierType,
+    },
+    reqwest::async_http_client as http_client,
+    url::Url,
+    AdditionalProviderMetadata, ProviderMetadata,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Tokens signed with an unknown key make us fetch the provider's keys again at most this often
+const MIN_KEY_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The identity provider could not be reached, or sent back something unusable
+#[derive(Debug)]
+pub struct ProviderUnavailable {
+    pub provider: String,
+    pub reason: String,
+}
+
+impl fmt::Display for ProviderUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Login provider {} is unavailable: {}",
+            self.provider, self.reason
+        )
+    }
+}
+
+impl std::error::Error for ProviderUnavailable {}
+
+/// Discovery fields for logging out, which `CoreProviderMetadata` leaves out
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LogoutProviderMetadata {
+    /// Where to send users to log out at the provider. Not every provider has one, e.g. Google
+    end_session_endpoint: Option<Url>,
+}
+
+impl AdditionalProviderMetadata for LogoutProviderMetadata {}
+
+type DiscoveredProviderMetadata = ProviderMetadata<
+    LogoutProviderMetadata,
+    CoreAuthDisplay,
+    CoreClientAuthMethod,
+    CoreClaimName,
+    CoreClaimType,
+    CoreGrantType,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJweKeyManagementAlgorithm,
+    CoreJwsSigningAlgorithm,
+    CoreJsonWebKeyType,
+    CoreJsonWebKeyUse,
+    CoreJsonWebKey,
+    CoreResponseMode,
+    CoreResponseType,
+    CoreSubjectIdentifierType,
+>;
+
+/// What discovery found out about a provider
+#[derive(Clone)]
+pub struct DiscoveredProvider {
+    pub client: CoreClient,
+    /// Keys the provider signs its tokens with
+    pub jwks: CoreJsonWebKeySet,
+    pub end_session_url: Option<Url>,
+}
+
+/// One `CoreClient` per provider, built from the provider's discovery document and JWKS.
+/// Discovery happens at startup and on refresh, not on every login
+#[derive(Default)]
+pub struct OidcClients {
+    providers: RwLock<HashMap<String, DiscoveredProvider>>,
+    /// When discovery of each provider was last attempted, successful or not
+    last_discovery: Mutex<HashMap<String, Instant>>,
+}
+
+impl OidcClients {
+    /// Discover every provider. Providers that are down are logged and retried on first use
+    pub async fn discover_all(&self, oidc_config: &OidcConfig) {
+        for provider in &oidc_config.providers {
+            // Failures are logged by refresh
+            let _ = self.refresh(provider).await;
+        }
+    }
+
+    /// The cached client for the provider, discovering it first if there is none yet
+    pub async fn get(&self, provider: &OidcProviderConfig) -> AnyhowResult<CoreClient> {
+        Ok(self.get_discovered(provider).await?.client)
+    }
+
+    /// Everything discovered about the provider, discovering it first if it was not yet
+    pub async fn get_discovered(
+        &self,
+        provider: &OidcProviderConfig,
+    ) -> AnyhowResult<DiscoveredProvider> {
+        let cached = self
+            .providers
+            .read()
+            .expect("OIDC client lock poisoned")
+            .get(&provider.name)
+            .cloned();
+        match cached {
+            Some(discovered) => Ok(discovered),
+            None => self.rediscover(provider).await,
+        }
+    }
+
+    /// Rediscover the provider, e.g. when it rotated its signing keys.
+    /// The old client is kept if discovery fails
+    pub async fn refresh(&self, provider: &OidcProviderConfig) -> AnyhowResult<CoreClient> {
+        Ok(self.rediscover(provider).await?.client)
+    }
+
+    /// Rediscover the provider because a token was signed with a key it does not have. Anyone can
+    /// send such a token, so None is returned without contacting the provider if it was
+    /// discovered within the last `MIN_KEY_REFRESH_INTERVAL`
+    pub async fn refresh_keys(
+        &self,
+        provider: &OidcProviderConfig,
+    ) -> AnyhowResult<Option<DiscoveredProvider>> {
+        let recently_discovered = self
+            .last_discovery
+            .lock()
+            .expect("OIDC discovery lock poisoned")
+            .get(&provider.name)
+            .is_some_and(|last| last.elapsed() < MIN_KEY_REFRESH_INTERVAL);
+        if recently_discovered {
+            return Ok(None);
+        }
+        Ok(Some(self.rediscover(provider).await?))
+    }
+
+    /// Like `refresh`, returning everything discovered rather than only the client
+    pub async fn rediscover(
+        &self,
+        provider: &OidcProviderConfig,
+    ) -> AnyhowResult<DiscoveredProvider> {
+        self.last_discovery
+            .lock()
+            .expect("OIDC discovery lock poisoned")
+            .insert(provider.name.clone(), Instant::now());
+        let provider_metadata =
+            DiscoveredProviderMetadata::discover_async(provider.issuer_url.clone(), http_client)
+                .await
+                .map_err(|e| {
+                    let e = anyhow::Error::new(e);
+                    warn!(
+                        "Failed to discover login provider {}: {:#}",
+                        provider.name, e
+                    );
+                    ProviderUnavailable {
+                        provider: provider.name.clone(),
+                        reason: e.to_string(),
+                    }
+                })?;
+        let jwks = provider_metadata.jwks().clone();
+        let end_session_url = provider_metadata
+            .additional_metadata()
+            .end_session_endpoint
+            .clone();
+        let client = CoreClient::from_provider_metadata(
+            provider_metadata,
+            provider.client_id.clone(),
+            Some(provider.client_secret.clone()),
+        )
+        .set_redirect_uri(provider.redirect_url.clone());
+        let discovered = DiscoveredProvider {
+            client,
+            jwks,
+            end_session_url,
+        };
+        self.providers
+            .write()
+            .expect("OIDC client lock poisoned")
+            .insert(provider.name.clone(), discovered.clone());
+        info!("Discovered login provider {}", provider.name);
+        Ok(discovered)
+    }
+
+    /// Rediscover every provider once per `ttl`, forever
+    pub async fn refresh_periodically(&self, oidc_config: &OidcConfig, ttl: Duration) {
+        let mut interval = tokio::time::interval(ttl);
+        // The first tick completes immediately, and startup already discovered everything
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            self.discover_all(oidc_config).await;
+        }
+    }
+}
+
+#[cfg(test)]
+impl OidcClients {
+    /// Cache the provider as if it was discovered just now, signing with the given keys
+    pub fn insert_discovered(&self, provider: &OidcProviderConfig, jwks: CoreJsonWebKeySet) {
+        let client = CoreClient::new(
+            provider.client_id.clone(),
+            Some(provider.client_secret.clone()),
+            provider.issuer_url.clone(),
+            openidconnect::AuthUrl::new(format!("{}/auth", provider.issuer_url.as_str())).unwrap(),
+            None,
+            None,
+            jwks.clone(),
+        );
+        self.providers.write().unwrap().insert(
+            provider.name.clone(),
+            DiscoveredProvider {
+                client,
+                jwks,
+                end_session_url: None,
+            },
+        );
+        self.last_discovery
+            .lock()
+            .unwrap()
+            .insert(provider.name.clone(), Instant::now());
+    }
+
+    /// Forget when the provider was discovered, so the next unknown key fetches its keys again
+    pub fn forget_discovery_time(&self, provider: &OidcProviderConfig) {
+        self.last_discovery.lock().unwrap().remove(&provider.name);
+    }
+}
@@ -0,0 +1,307 @@
+use crate::database::{self, PendingLoginRow};
+use anyhow::{anyhow, Context, Result as AnyhowResult};
+use chrono::{prelude::*, Duration as ChronoDuration};
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    SqliteConnection,
+};
+use log::{debug, error};
+use openidconnect::{CsrfToken, Nonce, PkceCodeVerifier};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// A login that was sent off to the identity provider and has not come back yet
+pub struct PendingLogin {
+    pub csrf_token: CsrfToken,
+    pub nonce: Nonce,
+    /// Sent with the code exchange, proves the callback belongs to this login
+    pub pkce_verifier: PkceCodeVerifier,
+    pub subapp: String,
+    /// Name of the provider the login was started with
+    pub provider: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl PendingLogin {
+    pub fn new(
+        csrf_token: CsrfToken,
+        nonce: Nonce,
+        pkce_verifier: PkceCodeVerifier,
+        subapp: String,
+        provider: String,
+    ) -> Self {
+        PendingLogin {
+            csrf_token,
+            nonce,
+            pkce_verifier,
+            subapp,
+            provider,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
+/// Where pending logins are kept between `trigger_login` and `token_exchange`, keyed by the
+/// anonymous user id in the session cookie. Entries older than the store's TTL count as missing
+pub trait PendingLoginStore: Send + Sync {
+    fn insert(&self, login_key: &str, pending_login: PendingLogin) -> AnyhowResult<()>;
+
+    /// Remove and return the pending login. Each login can only be completed once
+    fn take(&self, login_key: &str) -> AnyhowResult<Option<PendingLogin>>;
+
+    /// Drop expired entries. Returns how many were dropped
+    fn remove_expired(&self) -> AnyhowResult<usize>;
+}
+
+/// Sweep expired pending logins every `interval`, forever
+pub async fn remove_expired_periodically(store: &dyn PendingLoginStore, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        match store.remove_expired() {
+            Ok(0) => {}
+            Ok(removed) => debug!("Removed {} expired pending logins", removed),
+            Err(e) => error!("Failed to remove expired pending logins: {:#}", e),
+        }
+    }
+}
+
+fn to_chrono(ttl: Duration) -> AnyhowResult<ChronoDuration> {
+    ChronoDuration::from_std(ttl).context("Pending login TTL is too long")
+}
+
+/// Keeps pending logins in process. Lost on restart and not shared between instances
+pub struct MemoryPendingLoginStore {
+    entries: Mutex<HashMap<String, PendingLogin>>,
+    ttl: ChronoDuration,
+    max_entries: usize,
+}
+
+impl MemoryPendingLoginStore {
+    /// Once `max_entries` is reached, the oldest entry makes room for the newest
+    pub fn new(ttl: Duration, max_entries: usize) -> AnyhowResult<Self> {
+        Ok(MemoryPendingLoginStore {
+            entries: Mutex::new(HashMap::new()),
+            ttl: to_chrono(ttl)?,
+            max_entries,
+        })
+    }
+
+    fn lock(&self) -> AnyhowResult<std::sync::MutexGuard<'_, HashMap<String, PendingLogin>>> {
+        self.entries
+            .lock()
+            .map_err(|_| anyhow!("Pending login lock poisoned"))
+    }
+
+    fn cutoff(&self) -> NaiveDateTime {
+        Utc::now().naive_utc() - self.ttl
+    }
+}
+
+impl PendingLoginStore for MemoryPendingLoginStore {
+    fn insert(&self, login_key: &str, pending_login: PendingLogin) -> AnyhowResult<()> {
+        let mut entries = self.lock()?;
+        if entries.len() >= self.max_entries && !entries.contains_key(login_key) {
+            let cutoff = self.cutoff();
+            entries.retain(|_, entry| entry.created_at > cutoff);
+            if entries.len() >= self.max_entries {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.created_at)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(login_key.to_string(), pending_login);
+        Ok(())
+    }
+
+    fn take(&self, login_key: &str) -> AnyhowResult<Option<PendingLogin>> {
+        let cutoff = self.cutoff();
+        Ok(self
+            .lock()?
+            .remove(login_key)
+            .filter(|entry| entry.created_at > cutoff))
+    }
+
+    fn remove_expired(&self) -> AnyhowResult<usize> {
+        let cutoff = self.cutoff();
+        let mut entries = self.lock()?;
+        let before = entries.len();
+        entries.retain(|_, entry| entry.created_at > cutoff);
+        Ok(before - entries.len())
+    }
+}
+
+/// Keeps pending logins in the database, so they survive restarts and are shared between
+/// instances using the same database
+pub struct SqlitePendingLoginStore {
+    db_connection_pool: Pool<ConnectionManager<SqliteConnection>>,
+    ttl: ChronoDuration,
+}
+
+impl SqlitePendingLoginStore {
+    pub fn new(
+        db_connection_pool: Pool<ConnectionManager<SqliteConnection>>,
+        ttl: Duration,
+    ) -> AnyhowResult<Self> {
+        Ok(SqlitePendingLoginStore {
+            db_connection_pool,
+            ttl: to_chrono(ttl)?,
+        })
+    }
+
+    fn cutoff(&self) -> NaiveDateTime {
+        Utc::now().naive_utc() - self.ttl
+    }
+}
+
+impl PendingLoginStore for SqlitePendingLoginStore {
+    fn insert(&self, login_key: &str, pending_login: PendingLogin) -> AnyhowResult<()> {
+        let db_connection = &mut self.db_connection_pool.get()?;
+        database::insert_pending_login(
+            db_connection,
+            PendingLoginRow {
+                login_key: login_key.to_string(),
+                csrf_token: pending_login.csrf_token.secret().to_string(),
+                nonce: pending_login.nonce.secret().to_string(),
+                pkce_verifier: pending_login.pkce_verifier.secret().to_string(),
+                subapp: pending_login.subapp,
+                provider: pending_login.provider,
+                created_at: pending_login.created_at,
+            },
+        )
+    }
+
+    fn take(&self, login_key: &str) -> AnyhowResult<Option<PendingLogin>> {
+        let db_connection = &mut self.db_connection_pool.get()?;
+        let row = database::take_pending_login(db_connection, login_key, self.cutoff())?;
+        Ok(row.map(|row| PendingLogin {
+            csrf_token: CsrfToken::new(row.csrf_token),
+            nonce: Nonce::new(row.nonce),
+            pkce_verifier: PkceCodeVerifier::new(row.pkce_verifier),
+            subapp: row.subapp,
+            provider: row.provider,
+            created_at: row.created_at,
+        }))
+    }
+
+    fn remove_expired(&self) -> AnyhowResult<usize> {
+        let db_connection = &mut self.db_connection_pool.get()?;
+        database::delete_pending_logins_before(db_connection, self.cutoff())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    const TTL: Duration = Duration::from_secs(600);
+
+    /// A pending login started `age` ago
+    fn pending_login(csrf_token: &str, age: ChronoDuration) -> PendingLogin {
+        let mut pending_login = PendingLogin::new(
+            CsrfToken::new(csrf_token.to_string()),
+            Nonce::new("nonce".to_string()),
+            PkceCodeVerifier::new("verifier".to_string()),
+            "demo".to_string(),
+            "test".to_string(),
+        );
+        pending_login.created_at -= age;
+        pending_login
+    }
+
+    fn csrf_token(pending_login: Option<PendingLogin>) -> Option<String> {
+        pending_login.map(|pending_login| pending_login.csrf_token.secret().to_string())
+    }
+
+    fn check_single_use(store: &dyn PendingLoginStore) {
+        store
+            .insert("user", pending_login("csrf", ChronoDuration::zero()))
+            .unwrap();
+        assert_eq!(csrf_token(store.take("user").unwrap()), Some("csrf".into()));
+        assert!(store.take("user").unwrap().is_none());
+        assert!(store.take("stranger").unwrap().is_none());
+    }
+
+    fn check_expiry(store: &dyn PendingLoginStore) {
+        let ttl = to_chrono(TTL).unwrap();
+        let expired = ttl + ChronoDuration::seconds(1);
+        store
+            .insert("expired", pending_login("old", expired))
+            .unwrap();
+        store
+            .insert(
+                "fresh",
+                pending_login("new", ttl - ChronoDuration::seconds(5)),
+            )
+            .unwrap();
+        store
+            .insert("swept", pending_login("old", expired))
+            .unwrap();
+        assert!(store.take("expired").unwrap().is_none());
+        assert_eq!(store.remove_expired().unwrap(), 1);
+        assert_eq!(store.remove_expired().unwrap(), 0);
+        assert_eq!(csrf_token(store.take("fresh").unwrap()), Some("new".into()));
+    }
+
+    #[test]
+    fn memory_logins_are_single_use() {
+        check_single_use(&MemoryPendingLoginStore::new(TTL, 10).unwrap());
+    }
+
+    #[test]
+    fn memory_logins_expire() {
+        check_expiry(&MemoryPendingLoginStore::new(TTL, 10).unwrap());
+    }
+
+    #[test]
+    fn sqlite_logins_are_single_use() {
+        check_single_use(
+            &SqlitePendingLoginStore::new(test_support::connection_pool(), TTL).unwrap(),
+        );
+    }
+
+    #[test]
+    fn sqlite_logins_expire() {
+        check_expiry(&SqlitePendingLoginStore::new(test_support::connection_pool(), TTL).unwrap());
+    }
+
+    #[test]
+    fn full_memory_store_evicts_the_oldest_login() {
+        let store = MemoryPendingLoginStore::new(TTL, 2).unwrap();
+        let minutes = ChronoDuration::minutes;
+        store
+            .insert("older", pending_login("1", minutes(2)))
+            .unwrap();
+        store.insert("old", pending_login("2", minutes(1))).unwrap();
+        // Replacing an existing key does not evict anything
+        store.insert("old", pending_login("3", minutes(1))).unwrap();
+        store.insert("new", pending_login("4", minutes(0))).unwrap();
+        assert!(store.take("older").unwrap().is_none());
+        assert_eq!(csrf_token(store.take("old").unwrap()), Some("3".into()));
+        assert_eq!(csrf_token(store.take("new").unwrap()), Some("4".into()));
+    }
+
+    #[test]
+    fn full_memory_store_drops_expired_logins_first() {
+        let store = MemoryPendingLoginStore::new(TTL, 2).unwrap();
+        let expired = to_chrono(TTL).unwrap() + ChronoDuration::seconds(1);
+        store
+            .insert("expired", pending_login("1", expired))
+            .unwrap();
+        store
+            .insert("valid", pending_login("2", ChronoDuration::minutes(5)))
+            .unwrap();
+        store
+            .insert("new", pending_login("3", ChronoDuration::zero()))
+            .unwrap();
+        assert_eq!(store.remove_expired().unwrap(), 0);
+        assert_eq!(csrf_token(store.take("valid").unwrap()), Some("2".into()));
+        assert_eq!(csrf_token(store.take("new").unwrap()), Some("3".into()));
+    }
+}
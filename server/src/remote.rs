@@ -0,0 +1,218 @@
+use crate::config::RemoteRouteConfig;
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::{anyhow, Context, Result as AnyhowResult};
+use common::CSRF_HEADER;
+use openidconnect::AccessToken;
+use reqwest::header::{self, HeaderMap, HeaderName};
+use std::time::Duration;
+
+/// Give up on downstream APIs that take longer than this to respond
+const REMOTE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Headers that only apply to a single connection, plus credentials that belong to us and not
+/// to the downstream API or the browser
+const SKIPPED_HEADERS: [HeaderName; 11] = [
+    header::CONNECTION,
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+    header::HOST,
+    header::CONTENT_LENGTH,
+    header::COOKIE,
+    header::SET_COOKIE,
+];
+
+/// The downstream APIs exposed under `/remote/{name}/*`, and the client used to call them
+pub struct RemoteRoutes {
+    routes: Vec<RemoteRouteConfig>,
+    client: reqwest::Client,
+}
+
+impl RemoteRoutes {
+    pub fn new(routes: Vec<RemoteRouteConfig>) -> AnyhowResult<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(REMOTE_TIMEOUT)
+            // Redirects are passed back to the browser rather than followed with our token
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .context("Failed to build remote API client")?;
+        Ok(RemoteRoutes { routes, client })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&RemoteRouteConfig> {
+        self.routes.iter().find(|route| route.name == name)
+    }
+
+    /// Send the request on to the target URL with the user's access token, and stream the
+    /// response back
+    pub async fn forward(
+        &self,
+        route: &RemoteRouteConfig,
+        target_url: reqwest::Url,
+        request: &HttpRequest,
+        body: web::Bytes,
+        access_token: &AccessToken,
+    ) -> AnyhowResult<HttpResponse> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in request.headers() {
+            if !is_skipped(name)
+                && name != header::AUTHORIZATION
+                && !name.as_str().eq_ignore_ascii_case(CSRF_HEADER)
+            {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+        let response = self
+            .client
+            .request(request.method().clone(), target_url)
+            .headers(headers)
+            .bearer_auth(access_token.secret())
+            .body(body)
+            .send()
+            .await
+            .with_context(|| format!("Failed to call remote route {}", route.name))?;
+
+        let mut builder = HttpResponse::build(response.status());
+        for (name, value) in response.headers() {
+            if !is_skipped(name) {
+                builder.append_header((name.clone(), value.clone()));
+            }
+        }
+        Ok(builder.streaming(response.bytes_stream()))
+    }
+}
+
+fn is_skipped(name: &HeaderName) -> bool {
+    SKIPPED_HEADERS.contains(name) || name.as_str() == "keep-alive"
+}
+
+/// The route's URL with whatever followed `/remote/{name}` appended, including the query
+pub fn target_url(route: &RemoteRouteConfig, request: &HttpRequest) -> AnyhowResult<reqwest::Url> {
+    let prefix = format!("/remote/{}", route.name);
+    let tail = request
+        .path()
+        .strip_prefix(&prefix)
+        .ok_or_else(|| anyhow!("{} is not under {}", request.path(), prefix))?;
+    let base = route.url.as_str().trim_end_matches('/');
+    let mut target = format!("{}{}", base, tail);
+    if !request.query_string().is_empty() {
+        target = format!("{}?{}", target, request.query_string());
+    }
+    let target =
+        reqwest::Url::parse(&target).with_context(|| format!("Invalid remote URL {}", target))?;
+    // Dot segments must not climb out of the route's URL
+    let base_path = route.url.path().trim_end_matches('/');
+    let stays_within_base = target.origin() == route.url.origin()
+        && (target.path() == base_path || target.path().starts_with(&format!("{}/", base_path)));
+    if !stays_within_base {
+        return Err(anyhow!("{} escapes remote route {}", target, route.name));
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{body, http::header, http::Method, test::TestRequest, App, HttpServer};
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+
+    type Received = Arc<Mutex<Option<header::HeaderMap>>>;
+
+    /// Serve a stub downstream API on a free local port. It remembers the headers it was sent
+    /// and streams the request body back in two chunks
+    fn start_upstream() -> (RemoteRouteConfig, Received) {
+        let received = Received::default();
+        let shared = web::Data::new(received.clone());
+        let server = HttpServer::new(move || {
+            App::new().app_data(shared.clone()).default_service(web::to(
+                |request: HttpRequest, body: web::Bytes, received: web::Data<Received>| async move {
+                    *received.lock().unwrap() = Some(request.headers().clone());
+                    let (mut sender, stream) = actix_web_lab::body::channel::<Infallible>();
+                    sender.send(web::Bytes::from_static(b"echo: ")).unwrap();
+                    sender.send(body).unwrap();
+                    HttpResponse::Ok().body(stream)
+                },
+            ))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let port = server.addrs()[0].port();
+        actix_web::rt::spawn(server.run());
+        let url = format!("http://127.0.0.1:{}/api", port);
+        (
+            RemoteRouteConfig::new("stub", &url, "true").unwrap(),
+            received,
+        )
+    }
+
+    fn route() -> RemoteRouteConfig {
+        RemoteRouteConfig::new("stub", "http://127.0.0.1:8081/api", "true").unwrap()
+    }
+
+    #[actix_web::test]
+    async fn forwards_with_bearer_token_and_streams_the_body_back() {
+        let (route, received) = start_upstream();
+        let remote_routes = RemoteRoutes::new(vec![route.clone()]).unwrap();
+        let request = TestRequest::with_uri("/remote/stub/items?page=2")
+            .method(Method::POST)
+            .insert_header((header::COOKIE, "id=session-key"))
+            .insert_header((header::AUTHORIZATION, "Bearer browser-token"))
+            .insert_header((CSRF_HEADER, "1"))
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_http_request();
+        let target = target_url(&route, &request).unwrap();
+        assert_eq!(target.path(), "/api/items");
+        assert_eq!(target.query(), Some("page=2"));
+
+        let response = remote_routes
+            .forward(
+                &route,
+                target,
+                &request,
+                web::Bytes::from_static(b"hello"),
+                &AccessToken::new("user-token".to_string()),
+            )
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "echo: hello");
+
+        let headers = received.lock().unwrap().take().unwrap();
+        assert_eq!(
+            headers.get_all(header::AUTHORIZATION).collect::<Vec<_>>(),
+            ["Bearer user-token"]
+        );
+        assert!(!headers.contains_key(header::COOKIE));
+        assert!(!headers.contains_key(CSRF_HEADER));
+        assert_eq!(headers.get(header::ACCEPT).unwrap(), "application/json");
+    }
+
+    #[test]
+    fn rejects_paths_that_climb_out_of_the_route() {
+        for uri in [
+            "/remote/stub/../..",
+            "/remote/stub/../admin",
+            "/remote/stub/%2e%2e/%2e%2e",
+            "/remote/stub/%2E%2E/admin",
+            "/remote/stub/.%2e/admin",
+        ] {
+            let request = TestRequest::with_uri(uri).to_http_request();
+            assert!(target_url(&route(), &request).is_err(), "{}", uri);
+        }
+    }
+
+    #[test]
+    fn allows_dot_segments_that_stay_within_the_route() {
+        let request = TestRequest::with_uri("/remote/stub/items/../users").to_http_request();
+        assert_eq!(
+            target_url(&route(), &request).unwrap().as_str(),
+            "http://127.0.0.1:8081/api/users"
+        );
+    }
+}
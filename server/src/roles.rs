@@ -0,0 +1,109 @@
+use crate::database;
+use anyhow::{anyhow, Result as AnyhowResult};
+use common::Role;
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    SqliteConnection,
+};
+use log::info;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Recorded as the granter of admins from `BOOTSTRAP_ADMINS`
+const BOOTSTRAP_GRANTER: &str = "bootstrap";
+
+/// Revoking the role would leave nobody able to manage roles
+#[derive(Debug)]
+pub struct LastAdmin;
+
+impl fmt::Display for LastAdmin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("The last admin cannot be revoked")
+    }
+}
+
+impl std::error::Error for LastAdmin {}
+
+/// Emails are compared case insensitively
+pub fn normalise_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Roles by email, with when they were read from the database
+type CachedRoles = HashMap<String, (Instant, Vec<Role>)>;
+
+/// Roles of users from the database, cached for a short while since every request needs them.
+/// Changes made through this instance apply immediately, other instances catch up within `ttl`
+pub struct RoleCache {
+    db_connection_pool: Pool<ConnectionManager<SqliteConnection>>,
+    ttl: Duration,
+    entries: Mutex<CachedRoles>,
+}
+
+impl RoleCache {
+    pub fn new(
+        db_connection_pool: Pool<ConnectionManager<SqliteConnection>>,
+        ttl: Duration,
+    ) -> Self {
+        RoleCache {
+            db_connection_pool,
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Make sure every email in the list is an admin. Admins are never revoked here, so removing
+    /// someone from the list does not take their role away
+    pub fn bootstrap_admins(&self, emails: &[String]) -> AnyhowResult<()> {
+        let db_connection = &mut self.db_connection_pool.get()?;
+        for email in emails {
+            let email = normalise_email(email);
+            database::grant_role(db_connection, &email, Role::Admin, BOOTSTRAP_GRANTER)?;
+            info!("Bootstrapped {} as admin", email);
+        }
+        Ok(())
+    }
+
+    pub fn get_roles(&self, email: &str) -> AnyhowResult<Vec<Role>> {
+        let email = normalise_email(email);
+        if let Some((fetched_at, roles)) = self.lock()?.get(&email) {
+            if fetched_at.elapsed() < self.ttl {
+                return Ok(roles.clone());
+            }
+        }
+        let roles = {
+            let db_connection = &mut self.db_connection_pool.get()?;
+            database::get_user_roles(db_connection, &email)?
+        };
+        let mut entries = self.lock()?;
+        // Drop users that have not been seen in a while, so the cache does not grow forever
+        entries.retain(|_, (fetched_at, _)| fetched_at.elapsed() < self.ttl);
+        entries.insert(email, (Instant::now(), roles.clone()));
+        Ok(roles)
+    }
+
+    pub fn grant(&self, email: &str, role: Role, granted_by: &str) -> AnyhowResult<()> {
+        let email = normalise_email(email);
+        let db_connection = &mut self.db_connection_pool.get()?;
+        database::grant_role(db_connection, &email, role, granted_by)?;
+        self.lock()?.remove(&email);
+        Ok(())
+    }
+
+    /// Returns whether the user had the role
+    pub fn revoke(&self, email: &str, role: Role) -> AnyhowResult<bool> {
+        let email = normalise_email(email);
+        let db_connection = &mut self.db_connection_pool.get()?;
+        let revoked = database::revoke_role(db_connection, &email, role)?;
+        self.lock()?.remove(&email);
+        Ok(revoked)
+    }
+
+    fn lock(&self) -> AnyhowResult<std::sync::MutexGuard<'_, CachedRoles>> {
+        self.entries
+            .lock()
+            .map_err(|_| anyhow!("Role cache lock poisoned"))
+    }
+}
@@ -1,12 +1,106 @@
 // @generated automatically by Diesel CLI.
 
 diesel::table! {
-    queue (id) {
+    casbin_rule (id) {
         id -> Integer,
+        ptype -> Text,
+        v0 -> Text,
+        v1 -> Text,
+        v2 -> Text,
+        v3 -> Text,
+        v4 -> Text,
+        v5 -> Text,
+    }
+}
+
+diesel::table! {
+    pending_logins (login_key) {
+        login_key -> Text,
+        csrf_token -> Text,
+        nonce -> Text,
+        pkce_verifier -> Text,
+        subapp -> Text,
+        provider -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    queues (id) {
+        id -> Integer,
+        slug -> Text,
+        name -> Text,
+        created_at -> Timestamp,
+        description -> Text,
+        owner -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    sessions (session_key) {
+        session_key -> Text,
+        email -> Nullable<Text>,
+        session_id -> Nullable<Text>,
+        state -> Text,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    tickets (id) {
+        id -> Integer,
+        queue_id -> Integer,
         user -> Text,
-        is_selected -> Bool,
-        is_processed -> Bool,
-        is_abandoned -> Bool,
+        status -> Text,
         updated_at -> Timestamp,
+        called_at -> Nullable<Timestamp>,
+        served_at -> Nullable<Timestamp>,
     }
 }
+
+diesel::table! {
+    user_roles (email, role) {
+        email -> Text,
+        role -> Text,
+        granted_by -> Text,
+        granted_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    user_tokens (session_id) {
+        session_id -> Text,
+        email -> Text,
+        provider -> Text,
+        access_token -> Text,
+        refresh_token -> Nullable<Text>,
+        expires_at -> Nullable<Timestamp>,
+        updated_at -> Timestamp,
+        id_token -> Nullable<Text>,
+        subject -> Nullable<Text>,
+        sid -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    users (email) {
+        email -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::joinable!(tickets -> queues (queue_id));
+diesel::joinable!(user_roles -> users (email));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    casbin_rule,
+    pending_logins,
+    queues,
+    sessions,
+    tickets,
+    user_roles,
+    user_tokens,
+    users,
+);
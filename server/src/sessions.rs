@@ -0,0 +1,439 @@
+use crate::database::{self, SessionRow, UpdateSession};
+use crate::tokens::SESSION_ID_KEY;
+use actix_session::storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError};
+use actix_web::cookie::time::Duration as CookieDuration;
+use anyhow::{anyhow, Context, Result as AnyhowResult};
+use chrono::{prelude::*, Duration as ChronoDuration};
+use common::SessionSummary;
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    SqliteConnection,
+};
+use log::{debug, error, info};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use uuid::Uuid;
+
+/// Session key holding the email of the logged in user
+const USER_KEY: &str = "user";
+/// How many revocations an SSE stream can fall behind before it checks the database instead
+const REVOCATION_BUFFER: usize = 64;
+
+type SessionState = HashMap<String, String>;
+
+/// Keeps session state in the database, so the cookie only carries a random key and sessions can
+/// be ended from the server
+#[derive(Clone)]
+pub struct SqliteSessionStore {
+    db_connection_pool: Pool<ConnectionManager<SqliteConnection>>,
+}
+
+impl SqliteSessionStore {
+    pub fn new(db_connection_pool: Pool<ConnectionManager<SqliteConnection>>) -> Self {
+        SqliteSessionStore { db_connection_pool }
+    }
+
+    /// Drop expired sessions. Returns how many were dropped
+    pub fn remove_expired(&self) -> AnyhowResult<usize> {
+        let db_connection = &mut self.db_connection_pool.get()?;
+        database::delete_sessions_before(db_connection, Utc::now().naive_utc())
+    }
+
+    fn insert(&self, session_state: &SessionState, ttl: &CookieDuration) -> AnyhowResult<String> {
+        let now = Utc::now().naive_utc();
+        let session_key = Uuid::new_v4().simple().to_string();
+        let db_connection = &mut self.db_connection_pool.get()?;
+        database::insert_session(
+            db_connection,
+            SessionRow {
+                session_key: session_key.clone(),
+                email: get_string(session_state, USER_KEY),
+                session_id: get_string(session_state, SESSION_ID_KEY),
+                state: serde_json::to_string(session_state)?,
+                created_at: now,
+                updated_at: now,
+                expires_at: now + to_chrono(ttl),
+            },
+        )?;
+        Ok(session_key)
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl SessionStore for SqliteSessionStore {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        let row = self
+            .db_connection_pool
+            .get()
+            .map_err(anyhow::Error::from)
+            .and_then(|mut db_connection| {
+                database::get_session(
+                    &mut db_connection,
+                    session_key.as_ref(),
+                    Utc::now().naive_utc(),
+                )
+            })
+            .map_err(LoadError::Other)?;
+        row.map(|row| serde_json::from_str(&row.state))
+            .transpose()
+            .map_err(|e| LoadError::Deserialization(e.into()))
+    }
+
+    async fn save(
+        &self,
+        session_state: SessionState,
+        ttl: &CookieDuration,
+    ) -> Result<SessionKey, SaveError> {
+        let session_key = self.insert(&session_state, ttl).map_err(SaveError::Other)?;
+        to_session_key(session_key).map_err(SaveError::Other)
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: SessionState,
+        ttl: &CookieDuration,
+    ) -> Result<SessionKey, UpdateError> {
+        let state = serde_json::to_string(&session_state)
+            .map_err(|e| UpdateError::Serialization(e.into()))?;
+        let email = get_string(&session_state, USER_KEY);
+        let session_id = get_string(&session_state, SESSION_ID_KEY);
+        let now = Utc::now().naive_utc();
+        let updated = self
+            .db_connection_pool
+            .get()
+            .map_err(anyhow::Error::from)
+            .and_then(|mut db_connection| {
+                database::update_session(
+                    &mut db_connection,
+                    session_key.as_ref(),
+                    UpdateSession {
+                        email: email.as_deref(),
+                        session_id: session_id.as_deref(),
+                        state: &state,
+                        updated_at: now,
+                        expires_at: now + to_chrono(ttl),
+                    },
+                )
+            })
+            .map_err(UpdateError::Other)?;
+        if updated {
+            return Ok(session_key);
+        }
+        // The session was revoked while the request ran, so its state must not come back
+        let session_key = self
+            .insert(&SessionState::new(), ttl)
+            .map_err(UpdateError::Other)?;
+        to_session_key(session_key).map_err(UpdateError::Other)
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &CookieDuration) -> AnyhowResult<()> {
+        let db_connection = &mut self.db_connection_pool.get()?;
+        database::update_session_expiry(
+            db_connection,
+            session_key.as_ref(),
+            Utc::now().naive_utc() + to_chrono(ttl),
+        )
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> AnyhowResult<()> {
+        let db_connection = &mut self.db_connection_pool.get()?;
+        database::delete_session(db_connection, session_key.as_ref())
+    }
+}
+
+/// Sweep expired sessions every `interval`, forever
+pub async fn remove_expired_periodically(store: &SqliteSessionStore, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        match store.remove_expired() {
+            Ok(0) => {}
+            Ok(removed) => debug!("Removed {} expired sessions", removed),
+            Err(e) => error!("Failed to remove expired sessions: {:#}", e),
+        }
+    }
+}
+
+/// Session state values are stored as JSON
+fn get_string(session_state: &SessionState, key: &str) -> Option<String> {
+    session_state
+        .get(key)
+        .and_then(|value| serde_json::from_str(value).ok())
+}
+
+fn to_chrono(ttl: &CookieDuration) -> ChronoDuration {
+    ChronoDuration::seconds(ttl.whole_seconds())
+}
+
+fn to_session_key(session_key: String) -> AnyhowResult<SessionKey> {
+    SessionKey::try_from(session_key).map_err(|e| anyhow!(e))
+}
+
+/// Lists and revokes the logged in sessions of users. Sessions are identified by the id their
+/// tokens are stored under, the session key itself never leaves the cookie
+pub struct SessionRegistry {
+    db_connection_pool: Pool<ConnectionManager<SqliteConnection>>,
+    revocations: broadcast::Sender<String>,
+}
+
+impl SessionRegistry {
+    pub fn new(db_connection_pool: Pool<ConnectionManager<SqliteConnection>>) -> Self {
+        SessionRegistry {
+            db_connection_pool,
+            revocations: broadcast::channel(REVOCATION_BUFFER).0,
+        }
+    }
+
+    pub fn list(
+        &self,
+        email: &str,
+        current_session_id: Option<&str>,
+    ) -> AnyhowResult<Vec<SessionSummary>> {
+        let db_connection = &mut self.db_connection_pool.get()?;
+        let rows = database::get_user_sessions(db_connection, email, Utc::now().naive_utc())?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| {
+                let id = row.session_id?;
+                Some(SessionSummary {
+                    current: current_session_id == Some(id.as_str()),
+                    id,
+                    created_at: row.created_at.timestamp(),
+                    updated_at: row.updated_at.timestamp(),
+                    expires_at: row.expires_at.timestamp(),
+                })
+            })
+            .collect())
+    }
+
+    /// End one session of the user. Returns false if the user has no such session
+    pub fn revoke(&self, email: &str, session_id: &str) -> AnyhowResult<bool> {
+        Ok(self.revoke_sessions(email, Some(session_id))? > 0)
+    }
+
+    /// End every session of the user. Returns how many were ended
+    pub fn revoke_all(&self, email: &str) -> AnyhowResult<usize> {
+        self.revoke_sessions(email, None)
+    }
+
+    fn revoke_sessions(&self, email: &str, session_id: Option<&str>) -> AnyhowResult<usize> {
+        let session_ids = {
+            let db_connection = &mut self.db_connection_pool.get()?;
+            database::revoke_sessions(db_connection, email, session_id)
+                .context("Failed to revoke sessions")?
+        };
+        self.notify(&session_ids);
+        info!("Revoked {} sessions of {}", session_ids.len(), email);
+        Ok(session_ids.len())
+    }
+
+    /// End the sessions a provider logged out through back-channel logout. Returns how many were
+    /// ended
+    pub fn revoke_provider_sessions(
+        &self,
+        provider: &str,
+        subject: Option<&str>,
+        sid: Option<&str>,
+    ) -> AnyhowResult<usize> {
+        let session_ids = {
+            let db_connection = &mut self.db_connection_pool.get()?;
+            database::revoke_provider_sessions(db_connection, provider, subject, sid)
+                .context("Failed to revoke sessions")?
+        };
+        self.notify(&session_ids);
+        info!(
+            "Provider {} logged out {} sessions",
+            provider,
+            session_ids.len()
+        );
+        Ok(session_ids.len())
+    }
+
+    fn notify(&self, session_ids: &[String]) {
+        for session_id in session_ids {
+            // No SSE streams is fine
+            let _ = self.revocations.send(session_id.clone());
+        }
+    }
+
+    /// Find out when the session is revoked
+    pub fn watch(&self, session_id: String) -> RevocationWatch {
+        RevocationWatch {
+            session_id,
+            receiver: self.revocations.subscribe(),
+            db_connection_pool: self.db_connection_pool.clone(),
+        }
+    }
+}
+
+pub struct RevocationWatch {
+    session_id: String,
+    receiver: broadcast::Receiver<String>,
+    db_connection_pool: Pool<ConnectionManager<SqliteConnection>>,
+}
+
+impl RevocationWatch {
+    /// Completes once the session is revoked on this instance
+    pub async fn revoked(&mut self) {
+        loop {
+            match self.receiver.recv().await {
+                Ok(session_id) if session_id == self.session_id => return,
+                Ok(_) => {}
+                // Too many revocations to tell whether ours was among them
+                Err(RecvError::Lagged(_)) => {
+                    let exists = self
+                        .db_connection_pool
+                        .get()
+                        .map_err(anyhow::Error::from)
+                        .and_then(|mut db_connection| {
+                            database::has_session(&mut db_connection, &self.session_id)
+                        });
+                    match exists {
+                        Ok(false) => return,
+                        Ok(true) => {}
+                        Err(e) => error!("Failed to check session: {:#}", e),
+                    }
+                }
+                Err(RecvError::Closed) => std::future::pending().await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::UserTokenRow;
+    use crate::test_support;
+
+    /// One session of `email` with id `session_id`, logged in at the provider as `sid`
+    fn log_in(registry: &SessionRegistry, email: &str, session_id: &str, sid: &str) {
+        let db_connection = &mut registry.db_connection_pool.get().unwrap();
+        let now = Utc::now().naive_utc();
+        database::insert_session(
+            db_connection,
+            SessionRow {
+                session_key: Uuid::new_v4().simple().to_string(),
+                email: Some(email.to_string()),
+                session_id: Some(session_id.to_string()),
+                state: "{}".to_string(),
+                created_at: now,
+                updated_at: now,
+                expires_at: now + ChronoDuration::days(1),
+            },
+        )
+        .unwrap();
+        database::upsert_user_tokens(
+            db_connection,
+            UserTokenRow {
+                session_id: session_id.to_string(),
+                email: email.to_string(),
+                provider: "test".to_string(),
+                access_token: "access".to_string(),
+                refresh_token: Some("refresh".to_string()),
+                expires_at: None,
+                updated_at: now,
+                id_token: None,
+                subject: Some(format!("sub-{}", email)),
+                sid: Some(sid.to_string()),
+            },
+        )
+        .unwrap();
+    }
+
+    fn session_ids(registry: &SessionRegistry, email: &str) -> Vec<String> {
+        let mut ids = registry
+            .list(email, None)
+            .unwrap()
+            .into_iter()
+            .map(|session| session.id)
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    fn has_tokens(registry: &SessionRegistry, session_id: &str) -> bool {
+        let db_connection = &mut registry.db_connection_pool.get().unwrap();
+        database::get_user_tokens(db_connection, session_id)
+            .unwrap()
+            .is_some()
+    }
+
+    fn registry() -> SessionRegistry {
+        let registry = SessionRegistry::new(test_support::connection_pool());
+        log_in(&registry, "a@example.com", "a1", "sid-a1");
+        log_in(&registry, "a@example.com", "a2", "sid-a2");
+        log_in(&registry, "b@example.com", "b1", "sid-b1");
+        registry
+    }
+
+    #[test]
+    fn revoke_ends_one_session_of_the_user() {
+        let registry = registry();
+        assert!(!registry.revoke("b@example.com", "a1").unwrap());
+        assert!(registry.revoke("a@example.com", "a1").unwrap());
+        assert!(!registry.revoke("a@example.com", "a1").unwrap());
+        assert_eq!(session_ids(&registry, "a@example.com"), ["a2"]);
+        assert!(!has_tokens(&registry, "a1"));
+        assert!(has_tokens(&registry, "a2"));
+    }
+
+    #[test]
+    fn revoke_all_ends_every_session_of_the_user() {
+        let registry = registry();
+        assert_eq!(registry.revoke_all("a@example.com").unwrap(), 2);
+        assert!(session_ids(&registry, "a@example.com").is_empty());
+        assert!(!has_tokens(&registry, "a1") && !has_tokens(&registry, "a2"));
+        assert_eq!(session_ids(&registry, "b@example.com"), ["b1"]);
+        assert_eq!(registry.revoke_all("a@example.com").unwrap(), 0);
+    }
+
+    #[test]
+    fn revoke_provider_sessions_matches_the_given_claims() {
+        let registry = registry();
+        let revoked = registry
+            .revoke_provider_sessions("test", None, Some("sid-a2"))
+            .unwrap();
+        assert_eq!(revoked, 1);
+        assert_eq!(session_ids(&registry, "a@example.com"), ["a1"]);
+        let revoked = registry
+            .revoke_provider_sessions("other", Some("sub-b@example.com"), None)
+            .unwrap();
+        assert_eq!(revoked, 0);
+        let revoked = registry
+            .revoke_provider_sessions("test", Some("sub-b@example.com"), None)
+            .unwrap();
+        assert_eq!(revoked, 1);
+        assert!(session_ids(&registry, "b@example.com").is_empty());
+        assert!(!has_tokens(&registry, "b1"));
+    }
+
+    #[actix_web::test]
+    async fn watch_completes_when_its_session_is_revoked() {
+        let registry = registry();
+        let mut watch_a1 = registry.watch("a1".to_string());
+        let mut watch_a2 = registry.watch("a2".to_string());
+        registry.revoke("a@example.com", "a1").unwrap();
+        tokio::time::timeout(Duration::from_secs(1), watch_a1.revoked())
+            .await
+            .expect("Revoked session was not noticed");
+        let still_open = tokio::time::timeout(Duration::from_millis(100), watch_a2.revoked()).await;
+        assert!(still_open.is_err());
+    }
+
+    #[actix_web::test]
+    async fn lagging_watch_checks_the_database() {
+        let registry = registry();
+        let mut watch = registry.watch("a1".to_string());
+        // Fill the buffer with other sessions, so the watch misses its own revocation
+        for _ in 0..REVOCATION_BUFFER {
+            registry.notify(&["unrelated".to_string()]);
+        }
+        registry.revoke("a@example.com", "a1").unwrap();
+        tokio::time::timeout(Duration::from_secs(1), watch.revoked())
+            .await
+            .expect("Revoked session was not noticed");
+    }
+}
@@ -0,0 +1,241 @@
+use crate::config::OidcConfig;
+use crate::database::{self, UserTokenRow};
+use crate::oidc::OidcClients;
+use actix_session::Session;
+use anyhow::{anyhow, Context, Result as AnyhowResult};
+use chrono::{prelude::*, Duration as ChronoDuration};
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    SqliteConnection,
+};
+use log::{info, warn};
+use openidconnect::{
+    core::CoreTokenResponse, reqwest::async_http_client as http_client, AccessToken,
+    OAuth2TokenResponse, RefreshToken, RequestTokenError,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Session key holding the id the user's tokens are stored under
+pub const SESSION_ID_KEY: &str = "session_id";
+/// Access tokens are refreshed when they expire within this many seconds
+const REFRESH_MARGIN_SECONDS: i64 = 60;
+
+/// Who a session's tokens were issued to. Stays the same when the tokens are refreshed
+pub struct TokenSubject {
+    pub email: String,
+    /// Name of the provider that issued the tokens
+    pub provider: String,
+    /// The ID token's `sub` claim. Unknown for sessions from before it was kept
+    pub subject: Option<String>,
+    /// The ID token's `sid` claim, if the provider identifies its sessions
+    pub sid: Option<String>,
+}
+
+/// Keeps the tokens from the identity provider on the server, keyed by session, so the browser
+/// only ever holds the session cookie. Access tokens are only refreshed when they are asked for,
+/// there is no background refresh
+pub struct TokenStore {
+    db_connection_pool: Pool<ConnectionManager<SqliteConnection>>,
+    /// Refresh tokens may only be usable once, so each session refreshes one at a time
+    refresh_locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl TokenStore {
+    pub fn new(db_connection_pool: Pool<ConnectionManager<SqliteConnection>>) -> Self {
+        TokenStore {
+            db_connection_pool,
+            refresh_locks: Mutex::default(),
+        }
+    }
+
+    /// Store the tokens from a successful code exchange
+    pub fn save(
+        &self,
+        session_id: &str,
+        token_subject: &TokenSubject,
+        token_response: &CoreTokenResponse,
+    ) -> AnyhowResult<()> {
+        let db_connection = &mut self.db_connection_pool.get()?;
+        database::upsert_user_tokens(
+            db_connection,
+            to_row(session_id, token_subject, token_response, None)?,
+        )
+    }
+
+    /// The tokens of a session, whether or not they expired
+    pub fn get(&self, session_id: &str) -> AnyhowResult<Option<UserTokenRow>> {
+        let db_connection = &mut self.db_connection_pool.get()?;
+        database::get_user_tokens(db_connection, session_id)
+    }
+
+    /// Forget the tokens of a session, e.g. on logout
+    pub fn remove(&self, session_id: &str) -> AnyhowResult<()> {
+        let db_connection = &mut self.db_connection_pool.get()?;
+        database::delete_user_tokens(db_connection, session_id)
+    }
+
+    /// A valid access token for the user of this session, refreshed first if it is about to
+    /// expire. None if the user is not logged in or has to log in again
+    pub async fn get_access_token(
+        &self,
+        oidc_config: &OidcConfig,
+        oidc_clients: &OidcClients,
+        session: &Session,
+    ) -> AnyhowResult<Option<AccessToken>> {
+        let session_id = match session.get::<String>(SESSION_ID_KEY)? {
+            Some(session_id) => session_id,
+            None => return Ok(None),
+        };
+        let tokens = {
+            let db_connection = &mut self.db_connection_pool.get()?;
+            database::get_user_tokens(db_connection, &session_id)?
+        };
+        match tokens {
+            Some(tokens) if !expires_soon(&tokens) => {
+                Ok(Some(AccessToken::new(tokens.access_token)))
+            }
+            Some(_) => self.refresh(oidc_config, oidc_clients, &session_id).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn refresh(
+        &self,
+        oidc_config: &OidcConfig,
+        oidc_clients: &OidcClients,
+        session_id: &str,
+    ) -> AnyhowResult<Option<AccessToken>> {
+        let refresh_lock = self
+            .refresh_locks
+            .lock()
+            .expect("Refresh lock poisoned")
+            .entry(session_id.to_string())
+            .or_default()
+            .clone();
+        let result = {
+            let _guard = refresh_lock.lock().await;
+            self.refresh_locked(oidc_config, oidc_clients, session_id)
+                .await
+        };
+        drop(refresh_lock);
+        // Forget the lock once no other request of the session is waiting on it
+        let mut refresh_locks = self.refresh_locks.lock().expect("Refresh lock poisoned");
+        if refresh_locks
+            .get(session_id)
+            .is_some_and(|refresh_lock| Arc::strong_count(refresh_lock) == 1)
+        {
+            refresh_locks.remove(session_id);
+        }
+        result
+    }
+
+    async fn refresh_locked(
+        &self,
+        oidc_config: &OidcConfig,
+        oidc_clients: &OidcClients,
+        session_id: &str,
+    ) -> AnyhowResult<Option<AccessToken>> {
+        // Someone else may have refreshed while we waited for the lock
+        let tokens = {
+            let db_connection = &mut self.db_connection_pool.get()?;
+            database::get_user_tokens(db_connection, session_id)?
+        };
+        let tokens = match tokens {
+            Some(tokens) if !expires_soon(&tokens) => {
+                return Ok(Some(AccessToken::new(tokens.access_token)))
+            }
+            Some(tokens) => tokens,
+            None => return Ok(None),
+        };
+        let refresh_token = match &tokens.refresh_token {
+            Some(refresh_token) => RefreshToken::new(refresh_token.clone()),
+            None => {
+                info!(
+                    "Access token of {} expired without a refresh token",
+                    tokens.email
+                );
+                self.remove(session_id)?;
+                return Ok(None);
+            }
+        };
+        let provider_config = oidc_config
+            .provider(&tokens.provider)
+            .ok_or_else(|| anyhow!("Tokens were issued by unknown provider {}", tokens.provider))?;
+        let client = oidc_clients.get(provider_config).await?;
+        let token_response = match client
+            .exchange_refresh_token(&refresh_token)
+            .request_async(http_client)
+            .await
+        {
+            Ok(token_response) => token_response,
+            // The provider no longer accepts the refresh token, so the user has to log in again
+            Err(RequestTokenError::ServerResponse(e)) => {
+                warn!("Failed to refresh tokens of {}: {}", tokens.email, e);
+                self.remove(session_id)?;
+                return Ok(None);
+            }
+            Err(e) => return Err(e).context("Failed to refresh access token"),
+        };
+        let token_subject = TokenSubject {
+            email: tokens.email.clone(),
+            provider: tokens.provider.clone(),
+            subject: tokens.subject.clone(),
+            sid: tokens.sid.clone(),
+        };
+        let row = to_row(session_id, &token_subject, &token_response, Some(tokens))?;
+        let access_token = AccessToken::new(row.access_token.clone());
+        let db_connection = &mut self.db_connection_pool.get()?;
+        database::upsert_user_tokens(db_connection, row)?;
+        Ok(Some(access_token))
+    }
+}
+
+fn expires_soon(tokens: &UserTokenRow) -> bool {
+    match tokens.expires_at {
+        Some(expires_at) => {
+            expires_at - ChronoDuration::seconds(REFRESH_MARGIN_SECONDS) <= Utc::now().naive_utc()
+        }
+        None => false,
+    }
+}
+
+/// Providers do not always send a new refresh token or ID token on refresh, in which case the
+/// previous ones are kept
+fn to_row(
+    session_id: &str,
+    token_subject: &TokenSubject,
+    token_response: &CoreTokenResponse,
+    previous: Option<UserTokenRow>,
+) -> AnyhowResult<UserTokenRow> {
+    let (previous_refresh_token, previous_id_token) = match previous {
+        Some(previous) => (previous.refresh_token, previous.id_token),
+        None => (None, None),
+    };
+    let now = Utc::now().naive_utc();
+    let expires_at = token_response
+        .expires_in()
+        .map(ChronoDuration::from_std)
+        .transpose()
+        .context("Token lifetime is too long")?
+        .map(|expires_in| now + expires_in);
+    Ok(UserTokenRow {
+        session_id: session_id.to_string(),
+        email: token_subject.email.clone(),
+        provider: token_subject.provider.clone(),
+        access_token: token_response.access_token().secret().to_string(),
+        refresh_token: token_response
+            .refresh_token()
+            .map(|refresh_token| refresh_token.secret().to_string())
+            .or(previous_refresh_token),
+        expires_at,
+        updated_at: now,
+        id_token: token_response
+            .extra_fields()
+            .id_token()
+            .map(|id_token| id_token.to_string())
+            .or(previous_id_token),
+        subject: token_subject.subject.clone(),
+        sid: token_subject.sid.clone(),
+    })
+}